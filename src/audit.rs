@@ -0,0 +1,130 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use chrono::{DateTime, Utc};
+use dropshot::HttpError;
+use serde::Serialize;
+use std::fmt;
+use tracing::{
+    Event, Subscriber,
+    field::{Field, Visit},
+};
+use tracing_subscriber::{
+    fmt::{FmtContext, FormatEvent, FormatFields, format::Writer},
+    registry::LookupSpan,
+};
+
+use crate::{endpoints::TokenRequest, oidc::Claims};
+
+/// Target of the tracing events carrying audit events, routed to the audit log.
+pub static AUDIT_TARGET: &str = "oidcx::audit";
+
+/// Record of a token exchange attempt, written to the audit log as a JSON object.
+#[derive(Debug, Serialize)]
+pub struct AuditEvent {
+    pub timestamp: DateTime<Utc>,
+    pub request_id: String,
+    /// Missing if the identity token couldn't be validated.
+    pub issuer: Option<String>,
+    pub subject: Option<String>,
+    pub token_service: &'static str,
+    /// The silo of Oxide requests, or the repositories of GitHub requests.
+    pub repositories_or_silo: Option<String>,
+    pub result: AuditResult,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "outcome", rename_all = "snake_case")]
+pub enum AuditResult {
+    Granted,
+    /// The identity token was rejected, or the policy doesn't allow the request.
+    Denied {
+        reason: String,
+    },
+    /// The request was allowed, but generating the token failed.
+    Error {
+        reason: String,
+    },
+}
+
+impl AuditResult {
+    pub fn denied(err: &HttpError) -> Self {
+        AuditResult::Denied {
+            reason: err.internal_message.clone(),
+        }
+    }
+
+    pub fn error(err: &HttpError) -> Self {
+        AuditResult::Error {
+            reason: err.internal_message.clone(),
+        }
+    }
+}
+
+impl AuditEvent {
+    pub fn new(
+        request_id: &str,
+        request: &TokenRequest,
+        claims: Option<&Claims>,
+        result: AuditResult,
+    ) -> Self {
+        AuditEvent {
+            timestamp: Utc::now(),
+            request_id: request_id.to_string(),
+            issuer: claims.and_then(Claims::iss).map(str::to_string),
+            subject: claims.and_then(Claims::sub).map(str::to_string),
+            token_service: request.service(),
+            repositories_or_silo: match request {
+                TokenRequest::Oxide(oxide) => Some(oxide.silo.clone()),
+                TokenRequest::GitHub(github) => Some(github.repositories.join(",")),
+                TokenRequest::GitHubPackages(packages) => Some(packages.repositories.join(",")),
+                TokenRequest::Aws(_) | TokenRequest::Gcp(_) => None,
+            },
+            result,
+        }
+    }
+
+    /// Write the event to the audit log, if one is configured.
+    pub fn emit(&self) {
+        match serde_json::to_string(self) {
+            Ok(event) => tracing::info!(target: AUDIT_TARGET, event = event.as_str()),
+            Err(err) => tracing::error!(?err, "Failed to serialize the audit event"),
+        }
+    }
+}
+
+/// Event formatter writing only the audit event, producing newline-delimited JSON.
+pub struct AuditFormat;
+
+impl<S, N> FormatEvent<S, N> for AuditFormat
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(
+        &self,
+        _ctx: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        let mut visitor = AuditEventVisitor(None);
+        event.record(&mut visitor);
+        match visitor.0 {
+            Some(event) => writeln!(writer, "{event}"),
+            None => Ok(()),
+        }
+    }
+}
+
+struct AuditEventVisitor(Option<String>);
+
+impl Visit for AuditEventVisitor {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "event" {
+            self.0 = Some(value.to_string());
+        }
+    }
+
+    fn record_debug(&mut self, _field: &Field, _value: &dyn fmt::Debug) {}
+}
@@ -0,0 +1,245 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use chrono::{DateTime, Duration, Utc};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use crate::endpoints::{SecretExtra, SecretToken, Token, TokenRequest};
+use crate::oidc::Claims;
+
+/// How long before they expire to stop handing out cached tokens, so that callers still have time
+/// to use them.
+static EXPIRY_MARGIN: Duration = Duration::minutes(10);
+
+/// How long to cache tokens whose expiration isn't known. GitHub installation tokens are valid for
+/// an hour.
+static DEFAULT_TOKEN_TTL: Duration = Duration::minutes(50);
+
+/// Tokens generated for an identity token (identified by its issuer and `jti` claim) and a
+/// request, returned again when the same identity token makes the same request. Only GitHub
+/// tokens are cached, as tokens for the same repositories and permissions are interchangeable,
+/// while other services issue tokens whose lifetime starts when they're generated.
+#[derive(Clone, Debug)]
+pub struct ResponseCache {
+    max_entries: usize,
+    entries: Arc<Mutex<HashMap<CacheKey, CachedToken>>>,
+}
+
+/// The issuer and `jti` of the identity token, and the request.
+type CacheKey = (String, String, TokenRequest);
+
+#[derive(Debug)]
+struct CachedToken {
+    token: Token,
+    /// When to stop handing out the token.
+    valid_until: DateTime<Utc>,
+    last_used_at: DateTime<Utc>,
+}
+
+impl ResponseCache {
+    pub fn new(max_entries: usize) -> Self {
+        Self {
+            max_entries,
+            entries: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub fn get(&self, claims: &Claims, request: &TokenRequest) -> Option<Token> {
+        self.get_at(claims, request, Utc::now())
+    }
+
+    fn get_at(&self, claims: &Claims, request: &TokenRequest, now: DateTime<Utc>) -> Option<Token> {
+        let key = cache_key(claims, request)?;
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries.get_mut(&key)?;
+        if entry.valid_until <= now {
+            entries.remove(&key);
+            return None;
+        }
+        entry.last_used_at = now;
+        Some(copy_token(&entry.token))
+    }
+
+    pub fn insert(&self, claims: &Claims, request: &TokenRequest, token: &Token) {
+        self.insert_at(claims, request, token, Utc::now());
+    }
+
+    fn insert_at(
+        &self,
+        claims: &Claims,
+        request: &TokenRequest,
+        token: &Token,
+        now: DateTime<Utc>,
+    ) {
+        let Some(key) = cache_key(claims, request) else {
+            return;
+        };
+        let valid_until = match token.expires_at {
+            Some(expires_at) => expires_at - EXPIRY_MARGIN,
+            None => now + DEFAULT_TOKEN_TTL,
+        };
+        if valid_until <= now {
+            return;
+        }
+
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|_, entry| entry.valid_until > now);
+        // Evict the least recently used entry to make room for the new one.
+        if entries.len() >= self.max_entries
+            && !entries.contains_key(&key)
+            && let Some(oldest) = entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used_at)
+                .map(|(key, _)| key.clone())
+        {
+            entries.remove(&oldest);
+        }
+
+        entries.insert(
+            key,
+            CachedToken {
+                token: copy_token(token),
+                valid_until,
+                last_used_at: now,
+            },
+        );
+    }
+}
+
+// Tokens can't be cloned, to avoid leaving copies of them around by mistake, but the cache needs
+// its own copy of the tokens it returns.
+fn copy_token(token: &Token) -> Token {
+    Token {
+        access_token: SecretToken::new(token.access_token.expose().into()),
+        extra: token
+            .extra
+            .as_ref()
+            .map(|extra| SecretExtra::new(extra.expose().clone())),
+        expires_at: token.expires_at,
+    }
+}
+
+fn cache_key(claims: &Claims, request: &TokenRequest) -> Option<CacheKey> {
+    match request {
+        TokenRequest::GitHub(_) | TokenRequest::GitHubPackages(_) => Some((
+            claims.iss()?.to_string(),
+            claims.jti()?.to_string(),
+            request.clone(),
+        )),
+        TokenRequest::Oxide(_) | TokenRequest::Aws(_) | TokenRequest::Gcp(_) => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn claims(iss: &str, jti: &str) -> Claims {
+        serde_json::from_value(serde_json::json!({ "iss": iss, "jti": jti })).unwrap()
+    }
+
+    fn github_request() -> TokenRequest {
+        serde_json::from_value(serde_json::json!({
+            "service": "github",
+            "repositories": ["oxidecomputer/oidcx"],
+            "permissions": ["contents:read"],
+        }))
+        .unwrap()
+    }
+
+    fn token(expires_at: Option<DateTime<Utc>>) -> Token {
+        Token {
+            access_token: SecretToken::new("ghs_cached".into()),
+            extra: None,
+            expires_at,
+        }
+    }
+
+    fn time(time: &str) -> DateTime<Utc> {
+        time.parse().unwrap()
+    }
+
+    #[test]
+    fn tokens_are_cached_until_shortly_before_they_expire() {
+        let cache = ResponseCache::new(10);
+        let claims = claims("https://token.actions.githubusercontent.com", "1");
+        let request = github_request();
+        let created = time("2026-10-14T12:00:00Z");
+        cache.insert_at(
+            &claims,
+            &request,
+            &token(Some(time("2026-10-14T12:30:00Z"))),
+            created,
+        );
+
+        let cached = cache.get_at(&claims, &request, time("2026-10-14T12:19:59Z"));
+        assert_eq!(cached.unwrap().access_token.expose(), "ghs_cached");
+        assert!(
+            cache
+                .get_at(&claims, &request, time("2026-10-14T12:20:00Z"))
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn tokens_without_expiration_are_cached_for_the_default_ttl() {
+        let cache = ResponseCache::new(10);
+        let claims = claims("https://token.actions.githubusercontent.com", "1");
+        let request = github_request();
+        let created = time("2026-10-14T12:00:00Z");
+        cache.insert_at(&claims, &request, &token(None), created);
+
+        let before = created + DEFAULT_TOKEN_TTL - Duration::seconds(1);
+        assert!(cache.get_at(&claims, &request, before).is_some());
+        assert!(
+            cache
+                .get_at(&claims, &request, created + DEFAULT_TOKEN_TTL)
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn tokens_about_to_expire_are_not_cached() {
+        let cache = ResponseCache::new(10);
+        let claims = claims("https://token.actions.githubusercontent.com", "1");
+        let request = github_request();
+        let now = time("2026-10-14T12:00:00Z");
+        cache.insert_at(
+            &claims,
+            &request,
+            &token(Some(time("2026-10-14T12:05:00Z"))),
+            now,
+        );
+        assert!(cache.get_at(&claims, &request, now).is_none());
+    }
+
+    #[test]
+    fn tokens_are_keyed_by_issuer_and_jti() {
+        let cache = ResponseCache::new(10);
+        let request = github_request();
+        let now = time("2026-10-14T12:00:00Z");
+        cache.insert_at(
+            &claims("https://token.actions.githubusercontent.com", "1"),
+            &request,
+            &token(None),
+            now,
+        );
+
+        assert!(
+            cache
+                .get_at(&claims("https://gitlab.com", "1"), &request, now)
+                .is_none()
+        );
+        assert!(
+            cache
+                .get_at(
+                    &claims("https://token.actions.githubusercontent.com", "2"),
+                    &request,
+                    now
+                )
+                .is_none()
+        );
+    }
+}
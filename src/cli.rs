@@ -0,0 +1,353 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use std::path::PathBuf;
+
+use crate::{
+    endpoints::TokenRequest,
+    oidc::Claims,
+    policy::{Policy, PolicyError},
+    settings::Settings,
+    token::{aws::AwsTokens, gcp::GcpTokens, github::GitHubTokens, oxide::OxideTokens},
+    util::error_chain,
+};
+
+static USAGE: &str = "usage: oidcx [--validate-config] [<config path>]\n       \
+                      oidcx test-policy [--policy <path>] [--config <path>] --claims <json> \
+                      --request <json>";
+static TEST_POLICY_USAGE: &str = "usage: oidcx test-policy [--policy <path>] [--config <path>] \
+                                  --claims <json> --request <json>";
+
+/// What the binary was asked to do.
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    Serve {
+        config_path: Option<String>,
+    },
+    ValidateConfig {
+        config_path: Option<String>,
+    },
+    /// Run `test-policy`, with the arguments following it.
+    TestPolicy(Vec<String>),
+}
+
+/// Parse the arguments of the binary, without the name of the binary itself. Flags can be passed
+/// before or after the configuration path, and unknown flags are rejected rather than mistaken for
+/// a path.
+pub fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Command, String> {
+    let mut validate = false;
+    let mut config_path = None;
+    let mut first = true;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "test-policy" if first => return Ok(Command::TestPolicy(args.collect())),
+            "--validate-config" => validate = true,
+            _ if arg.starts_with('-') => return Err(format!("unknown flag {arg}\n{USAGE}")),
+            _ if config_path.is_some() => {
+                return Err(format!("unexpected argument {arg}\n{USAGE}"));
+            }
+            _ => config_path = Some(arg),
+        }
+        first = false;
+    }
+    Ok(if validate {
+        Command::ValidateConfig { config_path }
+    } else {
+        Command::Serve { config_path }
+    })
+}
+
+/// Evaluate the policy against the claims and request passed as arguments, printing `ALLOWED` or
+/// `DENIED: <reason>`. Returns the exit code: 0 if allowed, 1 if denied and 2 on errors.
+///
+/// Policies checking the repository metadata or the number of projects in a silo need the GitHub
+/// App or silo credentials to fetch them, which are read from the configuration file passed with
+/// `--config` (whose policy is also used if `--policy` is missing).
+pub async fn test_policy(args: impl Iterator<Item = String>) -> i32 {
+    match evaluate(args).await {
+        Ok(Ok(())) => {
+            println!("ALLOWED");
+            0
+        }
+        Ok(Err(reason)) => {
+            println!("DENIED: {reason}");
+            1
+        }
+        Err(message) => {
+            eprintln!("error: {message}");
+            2
+        }
+    }
+}
+
+async fn evaluate(mut args: impl Iterator<Item = String>) -> Result<Result<(), String>, String> {
+    let mut policy_path = None;
+    let mut config_path = None;
+    let mut claims = None;
+    let mut request = None;
+    while let Some(arg) = args.next() {
+        let target = match arg.as_str() {
+            "--policy" => &mut policy_path,
+            "--config" => &mut config_path,
+            "--claims" => &mut claims,
+            "--request" => &mut request,
+            _ => return Err(format!("unexpected argument {arg}\n{TEST_POLICY_USAGE}")),
+        };
+        *target = Some(
+            args.next()
+                .ok_or_else(|| format!("missing value for {arg}\n{TEST_POLICY_USAGE}"))?,
+        );
+    }
+
+    let claims: Claims = serde_json::from_str(
+        &claims.ok_or_else(|| format!("missing --claims\n{TEST_POLICY_USAGE}"))?,
+    )
+    .map_err(|err| format!("invalid claims: {err}"))?;
+    let request: TokenRequest = serde_json::from_str(
+        &request.ok_or_else(|| format!("missing --request\n{TEST_POLICY_USAGE}"))?,
+    )
+    .map_err(|err| format!("invalid request: {err}"))?;
+
+    let settings = config_path
+        .map(|path| Settings::new(Some(vec![path])))
+        .transpose()
+        .map_err(|err| error_chain(&err))?;
+    let policy_path = policy_path
+        .map(PathBuf::from)
+        .or_else(|| {
+            settings
+                .as_ref()
+                .map(|settings| settings.policy_path.clone())
+        })
+        .ok_or_else(|| format!("missing --policy\n{TEST_POLICY_USAGE}"))?;
+    let (github_tokens, oxide_tokens) = match &settings {
+        Some(settings) => (
+            GitHubTokens::new(settings).map_err(|err| error_chain(&err))?,
+            OxideTokens::new(settings).map_err(|err| error_chain(&err))?,
+        ),
+        None => (GitHubTokens::default(), OxideTokens::default()),
+    };
+
+    let policy = Policy::new(
+        &policy_path,
+        github_tokens,
+        oxide_tokens,
+        chrono::Duration::zero(),
+    )
+    .map_err(|err| error_chain(&err))?;
+    match policy.ensure_allowed(&claims, &request).await {
+        Ok(()) => Ok(Ok(())),
+        Err(err @ PolicyError::NotMatching(_)) => Ok(Err(err.to_string())),
+        Err(err) => Err(error_chain(&err)),
+    }
+}
+
+/// Check the configuration without starting the server: the settings are loaded and validated, and
+/// the policy and credentials are loaded, but providers and silos are not contacted. Prints what
+/// was checked, and returns the exit code: 0 if everything is valid and 1 otherwise.
+pub fn validate_config(config_path: Option<String>) -> i32 {
+    let settings = match Settings::new(config_path.map(|path| vec![path])) {
+        Ok(settings) => settings,
+        Err(err) => {
+            eprintln!("error: failed to load the settings: {}", error_chain(&err));
+            return 1;
+        }
+    };
+    println!("ok: loaded the settings");
+
+    let mut errors = Vec::new();
+    match settings.validate() {
+        Ok(()) => println!("ok: validated the settings"),
+        Err(validation_errors) => {
+            errors.extend(validation_errors.iter().map(|err| error_chain(err)));
+        }
+    }
+    // The files were already reported as unreadable by the validation.
+    if std::fs::File::open(&settings.policy_path).is_ok() {
+        match Policy::new(
+            &settings.policy_path,
+            GitHubTokens::default(),
+            OxideTokens::default(),
+            chrono::Duration::zero(),
+        ) {
+            Ok(_) => println!(
+                "ok: loaded the policy at {}",
+                settings.policy_path.display()
+            ),
+            Err(err) => errors.push(format!(
+                "failed to load the policy at {}: {}",
+                settings.policy_path.display(),
+                error_chain(&err)
+            )),
+        }
+    }
+    if settings.github.is_some() {
+        match GitHubTokens::new(&settings) {
+            Ok(_) => println!("ok: loaded the GitHub App credentials"),
+            Err(err) => errors.push(error_chain(&err)),
+        }
+    }
+    if settings.oxide.is_some() {
+        match OxideTokens::new(&settings) {
+            Ok(_) => println!("ok: loaded the silo credentials"),
+            Err(err) => errors.push(error_chain(&err)),
+        }
+    }
+    if settings.aws.is_some() {
+        match AwsTokens::new(&settings) {
+            Ok(_) => println!("ok: configured the AWS client"),
+            Err(err) => errors.push(error_chain(&err)),
+        }
+    }
+    if settings.gcp.is_some() {
+        match GcpTokens::new(&settings) {
+            Ok(_) => println!("ok: loaded the GCP credentials"),
+            Err(err) => errors.push(error_chain(&err)),
+        }
+    }
+    println!(
+        "skipped: fetching the configuration of {} provider(s)",
+        settings.providers.len()
+    );
+
+    for error in &errors {
+        eprintln!("error: {error}");
+    }
+    if errors.is_empty() { 0 } else { 1 }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::temp_file;
+
+    fn parse(args: &[&str]) -> Result<Command, String> {
+        parse_args(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn flags_are_parsed_anywhere() {
+        assert_eq!(parse(&[]), Ok(Command::Serve { config_path: None }));
+        assert_eq!(
+            parse(&["config.toml"]),
+            Ok(Command::Serve {
+                config_path: Some("config.toml".into())
+            })
+        );
+        for args in [
+            ["--validate-config", "config.toml"],
+            ["config.toml", "--validate-config"],
+        ] {
+            assert_eq!(
+                parse(&args),
+                Ok(Command::ValidateConfig {
+                    config_path: Some("config.toml".into())
+                })
+            );
+        }
+        assert_eq!(
+            parse(&["test-policy", "--claims", "{}"]),
+            Ok(Command::TestPolicy(vec!["--claims".into(), "{}".into()]))
+        );
+    }
+
+    #[test]
+    fn unknown_and_misplaced_arguments_are_rejected() {
+        let cases: [&[&str]; 4] = [
+            &["--validate"],
+            &["config.toml", "--verbose"],
+            &["config.toml", "other.toml"],
+            &["config.toml", "test-policy"],
+        ];
+        for args in cases {
+            assert!(parse(args).is_err(), "{args:?}");
+        }
+    }
+
+    /// Write settings using `policy`, returning the paths of the settings and the policy.
+    fn config_with_policy(policy: &str) -> (PathBuf, PathBuf) {
+        let policy_path = temp_file("policy.polar", policy);
+        let config_path = temp_file(
+            "settings.toml",
+            &format!(
+                r#"
+                audiences = ["oidcx"]
+                policy_path = "{}"
+
+                [[providers]]
+                url = "https://token.actions.githubusercontent.com/.well-known/openid-configuration"
+                "#,
+                policy_path.display()
+            ),
+        );
+        (config_path, policy_path)
+    }
+
+    #[test]
+    fn invalid_policies_fail_the_validation() {
+        for (policy, expected) in [
+            ("allow_request(_claims, _request);", 0),
+            ("allow_request(_claims, _request) if", 1),
+        ] {
+            let (config_path, policy_path) = config_with_policy(policy);
+            let code = validate_config(Some(config_path.display().to_string()));
+            std::fs::remove_file(&config_path).unwrap();
+            std::fs::remove_file(&policy_path).unwrap();
+            assert_eq!(code, expected, "{policy}");
+        }
+    }
+
+    static CLAIMS: &str = r#"{"iss": "https://token.actions.githubusercontent.com"}"#;
+    static REQUEST: &str =
+        r#"{"service": "oxide", "silo": "https://example.sys.oxide.computer", "duration": 3600}"#;
+
+    /// Run `test-policy` with `args` after `--policy` pointing to a file containing `policy`.
+    async fn run(policy: &str, args: &[&str]) -> i32 {
+        let path = temp_file("policy.polar", policy);
+        let policy_arg = path.display().to_string();
+        let args = ["--policy", policy_arg.as_str()]
+            .into_iter()
+            .chain(args.iter().copied())
+            .map(String::from)
+            .collect::<Vec<_>>();
+        let code = test_policy(args.into_iter()).await;
+        std::fs::remove_file(&path).unwrap();
+        code
+    }
+
+    #[tokio::test]
+    async fn allowed_requests_exit_with_0() {
+        let code = run(
+            "allow_request(_claims, _request);",
+            &["--claims", CLAIMS, "--request", REQUEST],
+        )
+        .await;
+        assert_eq!(code, 0);
+    }
+
+    #[tokio::test]
+    async fn denied_requests_exit_with_1() {
+        let code = run(
+            "allow_request(_claims, _request) if false;",
+            &["--claims", CLAIMS, "--request", REQUEST],
+        )
+        .await;
+        assert_eq!(code, 1);
+    }
+
+    #[tokio::test]
+    async fn malformed_arguments_exit_with_2() {
+        let policy = "allow_request(_claims, _request);";
+        let cases: [&[&str]; 5] = [
+            &["--claims", "not json", "--request", REQUEST],
+            &["--claims", CLAIMS, "--request", r#"{"service": "custom"}"#],
+            &["--claims", CLAIMS],
+            &["--claims", CLAIMS, "--request"],
+            &["--claims", CLAIMS, "--request", REQUEST, "--verbose"],
+        ];
+        for args in cases {
+            assert_eq!(run(policy, args).await, 2, "{args:?}");
+        }
+    }
+}
@@ -5,33 +5,79 @@
 use std::{
     collections::HashMap,
     error::Error as StdError,
-    sync::{Arc, RwLock},
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, Instant},
 };
 use thiserror::Error;
 
 use crate::{
+    cache::ResponseCache,
+    endpoints::openapi_description,
+    nonce::NonceCache,
     oidc::{OidcError, ResolvedOidcConfig},
     policy::Policy,
-    settings::Settings,
+    settings::{Settings, SettingsValidationError},
     token::{
+        aws::{AwsError, AwsTokens},
+        gcp::{GcpError, GcpTokens},
         github::{GitHubTokenError, GitHubTokens},
         oxide::{OxideError, OxideTokens},
     },
 };
+use config::ConfigError;
 use oso::OsoError;
+use schemars::JsonSchema;
+use serde::Serialize;
 
 #[derive(Debug, Error)]
 pub enum ContextBuildError {
+    #[error("Failed to read the settings")]
+    Settings(#[from] ConfigError),
+    #[error("The settings are invalid: {}", format_validation_errors(.0))]
+    InvalidSettings(Vec<SettingsValidationError>),
     #[error("Failed to construct client")]
     ClientConstruction(Box<dyn StdError + Send + Sync>),
     #[error("Failed to initialize the Oxide token store")]
     OxideTokens(#[from] OxideError),
     #[error("Failed to initialize the GitHub token store")]
-    GitHubTokens(#[from] GitHubTokenError),
-    #[error("Encountered an error configuring OIDC providers")]
-    Oidc(#[from] OidcError),
+    GitHubTokens {
+        #[from]
+        source: GitHubTokenError,
+    },
+    #[error("Failed to reach the silo {silo}")]
+    SiloUnreachable {
+        silo: String,
+        #[source]
+        source: OxideError,
+    },
+    #[error("Failed to initialize the AWS token store")]
+    AwsTokens(#[from] AwsError),
+    #[error("Failed to initialize the GCP token store")]
+    GcpTokens(#[from] GcpError),
+    #[error("Failed to fetch the OIDC configuration from {url}")]
+    Oidc {
+        url: String,
+        #[source]
+        source: OidcError,
+    },
     #[error("Failed to initialize the Oso policy")]
     Oso(#[from] OsoError),
+    #[error("Failed to generate the OpenAPI description")]
+    OpenApi(#[from] serde_json::Error),
+    #[error("GitHub App {client_id} has ID {actual}, but {expected} was expected")]
+    AppIdMismatch {
+        client_id: String,
+        expected: u64,
+        actual: u64,
+    },
+}
+
+fn format_validation_errors(errors: &[SettingsValidationError]) -> String {
+    errors
+        .iter()
+        .map(|err| err.to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
 }
 
 #[derive(Debug)]
@@ -39,40 +85,432 @@ pub struct ResolvedOidcProvider {
     pub config: ResolvedOidcConfig,
 }
 
-#[derive(Debug)]
+/// Shared state of the server. Cloning it is cheap, as all clones point to the same state.
+///
+/// The state is replaced as a whole when the settings are reloaded. Requests should take a
+/// snapshot with [`Context::state`] once and use it throughout, so they never see the providers of
+/// one configuration with the policy of another.
+///
+/// There is no `Default` implementation, as building the state requires the settings and fetching
+/// the configuration of every provider.
+#[derive(Clone, Debug)]
 pub struct Context {
+    state: Arc<RwLock<Arc<ContextInner>>>,
+    in_flight: InFlightRequests,
+}
+
+#[derive(Debug)]
+pub struct ContextInner {
     pub settings: Settings,
     pub providers: HashMap<String, Arc<RwLock<ResolvedOidcProvider>>>,
     pub oxide_tokens: OxideTokens,
     pub github_tokens: GitHubTokens,
+    pub aws_tokens: AwsTokens,
+    pub gcp_tokens: GcpTokens,
     pub policy: Policy,
+    pub nonces: NonceCache,
+    /// Client used to fetch the configuration and keys of the providers.
+    pub oidc_client: reqwest::Client,
+    pub response_cache: Option<ResponseCache>,
+    /// The OpenAPI description of the service, which only changes with the code.
+    pub openapi: Arc<serde_json::Value>,
+}
+
+/// Exchanges currently running, to report the ones dropped if they don't complete before the
+/// shutdown timeout.
+#[derive(Clone, Debug, Default)]
+pub struct InFlightRequests(Arc<Mutex<HashMap<String, InFlightRequest>>>);
+
+#[derive(Clone, Debug)]
+pub struct InFlightRequest {
+    pub request_id: String,
+    pub route: &'static str,
+    pub started_at: Instant,
+}
+
+impl InFlightRequests {
+    /// Record the request as running until the returned guard is dropped.
+    pub fn track(&self, request_id: &str, route: &'static str) -> InFlightGuard {
+        self.0.lock().unwrap().insert(
+            request_id.into(),
+            InFlightRequest {
+                request_id: request_id.into(),
+                route,
+                started_at: Instant::now(),
+            },
+        );
+        InFlightGuard {
+            requests: self.clone(),
+            request_id: request_id.into(),
+        }
+    }
+
+    /// The requests still running, oldest first.
+    pub fn list(&self) -> Vec<InFlightRequest> {
+        let mut requests = self.0.lock().unwrap().values().cloned().collect::<Vec<_>>();
+        requests.sort_by_key(|request| request.started_at);
+        requests
+    }
+}
+
+/// Removes the request from the in-flight requests once it completes, or is cancelled.
+pub struct InFlightGuard {
+    requests: InFlightRequests,
+    request_id: String,
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.requests.0.lock().unwrap().remove(&self.request_id);
+    }
+}
+
+/// What changed when the settings were reloaded.
+#[derive(Debug)]
+pub struct ReloadSummary {
+    pub added_issuers: Vec<String>,
+    pub removed_issuers: Vec<String>,
+    /// Settings that changed, but only take effect after a restart.
+    pub restart_required: Vec<&'static str>,
 }
 
 impl Context {
     pub async fn new(settings: Settings) -> Result<Self, ContextBuildError> {
-        let client = reqwest::Client::new();
+        let inner = ContextInner::build(settings, None).await?;
+        Ok(Context {
+            state: Arc::new(RwLock::new(Arc::new(inner))),
+            in_flight: InFlightRequests::default(),
+        })
+    }
+
+    /// Take a snapshot of the current state.
+    pub fn state(&self) -> Arc<ContextInner> {
+        self.state.read().unwrap().clone()
+    }
+
+    /// The exchanges currently running, which outlive reloads of the state.
+    pub fn in_flight(&self) -> &InFlightRequests {
+        &self.in_flight
+    }
+
+    /// Read the settings again and replace the whole state with one built from them. Everything is
+    /// built before the swap, so if anything fails the previous state stays in use untouched.
+    /// Requests already running keep using the state they started with.
+    pub async fn reload(
+        &self,
+        config_sources: Option<Vec<String>>,
+    ) -> Result<ReloadSummary, ContextBuildError> {
+        let settings = Settings::new(config_sources)?;
+        settings
+            .validate()
+            .map_err(ContextBuildError::InvalidSettings)?;
+
+        let previous = self.state();
+        let inner = ContextInner::build(settings, Some(&previous)).await?;
+        let summary = ReloadSummary::new(&previous, &inner);
+        *self.state.write().unwrap() = Arc::new(inner);
+        Ok(summary)
+    }
+}
+
+impl ContextInner {
+    /// Build the state from the settings. When reloading, the previous state is passed to carry
+    /// over the nonces already seen, so a reload can't be used to replay a token.
+    async fn build(
+        settings: Settings,
+        previous: Option<&ContextInner>,
+    ) -> Result<Self, ContextBuildError> {
+        let client = settings
+            .proxy
+            .apply(reqwest::Client::builder())
+            .and_then(|builder| {
+                builder
+                    .timeout(Duration::from_secs(settings.oidc_startup_timeout_seconds))
+                    .build()
+            })
+            .map_err(|err| ContextBuildError::ClientConstruction(Box::new(err)))?;
 
         let mut providers = HashMap::new();
         for provider in &settings.providers {
-            let resolved = ResolvedOidcProvider {
-                config: provider
-                    .fetch_config(&client)
-                    .await?
-                    .resolve(&client)
-                    .await?,
+            let config = match provider.fetch_resolved_config(&client).await {
+                Ok(config) => config,
+                Err(err) => {
+                    tracing::error!(
+                        url = provider.url(),
+                        error = ?err,
+                        "Failed to fetch the configuration of the provider"
+                    );
+                    if settings.fail_on_provider_startup_error {
+                        return Err(ContextBuildError::Oidc {
+                            url: provider.url().into(),
+                            source: err,
+                        });
+                    }
+                    tracing::warn!(url = provider.url(), "Skipping the provider");
+                    continue;
+                }
             };
-            let issuer = resolved.config.issuer.clone();
-            providers.insert(issuer, Arc::new(RwLock::new(resolved)));
+            tracing::info!(
+                url = provider.url(),
+                issuer = config.issuer(),
+                "Fetched the configuration of the provider"
+            );
+            let issuer = config.issuer().to_string();
+            providers.insert(
+                issuer,
+                Arc::new(RwLock::new(ResolvedOidcProvider { config })),
+            );
         }
 
         let github_tokens = GitHubTokens::new(&settings)?;
+        if let Some(github) = &settings.github {
+            let expected_ids = github
+                .apps()
+                .filter_map(|app| Some((app.client_id.as_str(), app.expected_app_id?)))
+                .collect::<HashMap<_, _>>();
+            if !expected_ids.is_empty() {
+                for app in github_tokens.get_app_metadata().await? {
+                    if let Some(&expected) = expected_ids.get(app.client_id.as_str())
+                        && expected != app.id
+                    {
+                        return Err(ContextBuildError::AppIdMismatch {
+                            client_id: app.client_id,
+                            expected,
+                            actual: app.id,
+                        });
+                    }
+                }
+            }
+        }
 
-        Ok(Context {
+        let oxide_tokens = OxideTokens::new(&settings)?;
+        if let Some(oxide) = &settings.oxide {
+            for (silo, result) in oxide_tokens.validate_silo_connectivity().await {
+                match result {
+                    Ok(()) => tracing::info!(silo, "Connected to the silo"),
+                    Err(err) if oxide.fail_on_unreachable_silo => {
+                        return Err(ContextBuildError::SiloUnreachable { silo, source: err });
+                    }
+                    Err(err) => tracing::warn!(silo, error = ?err, "Failed to reach the silo"),
+                }
+            }
+        }
+
+        let nonces = previous
+            .map(|previous| previous.nonces.clone())
+            .unwrap_or_default();
+        let openapi = match previous {
+            Some(previous) => previous.openapi.clone(),
+            None => Arc::new(openapi_description()?),
+        };
+        let evicting = previous.is_some_and(|previous| previous.settings.enable_replay_prevention);
+        if settings.enable_replay_prevention && !evicting {
+            nonces.spawn_eviction();
+        }
+
+        let policy = Policy::new(
+            &settings.policy_path,
+            github_tokens.clone(),
+            oxide_tokens.clone(),
+            chrono::Duration::seconds(
+                settings
+                    .github
+                    .as_ref()
+                    .and_then(|github| github.visibility_cache_ttl_seconds)
+                    .unwrap_or(3600) as _,
+            ),
+        )?;
+        if settings.policy_reload_on_change {
+            policy.spawn_reload_on_change();
+        }
+
+        Ok(ContextInner {
             providers,
-            policy: Policy::new(&settings.policy_path, github_tokens.clone())?,
-            oxide_tokens: OxideTokens::new(&settings)?,
+            policy,
+            oxide_tokens,
             github_tokens,
+            aws_tokens: AwsTokens::new(&settings)?,
+            gcp_tokens: GcpTokens::new(&settings)?,
+            nonces,
+            oidc_client: client,
+            response_cache: settings
+                .enable_response_cache
+                .then(|| ResponseCache::new(settings.response_cache_max_entries)),
+            openapi,
             settings,
         })
     }
+
+    /// Describe the configuration of the providers in use, sorted by issuer.
+    pub fn provider_states(&self) -> Vec<ProviderState> {
+        let mut states = self
+            .providers
+            .values()
+            .map(|provider| {
+                let config = &provider.read().unwrap().config;
+                ProviderState {
+                    issuer: config.issuer().to_string(),
+                    jwks_uri: config.jwks_uri().to_string(),
+                    key_count: config.key_count(),
+                    last_refreshed_at: config.fetched_at().to_rfc3339(),
+                    supported_algorithms: config
+                        .supported_algorithms()
+                        .iter()
+                        .map(|algorithm| format!("{algorithm:?}"))
+                        .collect(),
+                    claims_supported: config.claims_supported().to_vec(),
+                }
+            })
+            .collect::<Vec<_>>();
+        states.sort_by(|a, b| a.issuer.cmp(&b.issuer));
+        states
+    }
+
+    /// Fetch the configuration and keys of every provider again, replacing the ones in use. The
+    /// set of issuers can't change without reloading the settings, so providers whose issuer
+    /// changed are reported as failed.
+    pub async fn refresh_providers(&self) -> Vec<ProviderRefresh> {
+        let mut results = Vec::new();
+        for provider in &self.settings.providers {
+            let result = match provider.fetch_resolved_config(&self.oidc_client).await {
+                Ok(config) => match self.providers.get(config.issuer()) {
+                    Some(resolved) => {
+                        let issuer = config.issuer().to_string();
+                        resolved.write().unwrap().config = config;
+                        Ok(issuer)
+                    }
+                    None => Err(format!(
+                        "issuer {} was not configured when the settings were loaded",
+                        config.issuer()
+                    )),
+                },
+                Err(err) => Err(err.to_string()),
+            };
+
+            if let Err(err) = &result {
+                tracing::warn!(url = provider.url(), err, "Failed to refresh provider");
+            }
+            results.push(ProviderRefresh {
+                url: provider.url().into(),
+                refreshed: result.is_ok(),
+                issuer: result.as_ref().ok().cloned(),
+                error: result.err(),
+            });
+        }
+        results
+    }
+}
+
+impl ReloadSummary {
+    fn new(previous: &ContextInner, current: &ContextInner) -> Self {
+        let mut added_issuers = current
+            .providers
+            .keys()
+            .filter(|issuer| !previous.providers.contains_key(*issuer))
+            .cloned()
+            .collect::<Vec<_>>();
+        added_issuers.sort();
+        let mut removed_issuers = previous
+            .providers
+            .keys()
+            .filter(|issuer| !current.providers.contains_key(*issuer))
+            .cloned()
+            .collect::<Vec<_>>();
+        removed_issuers.sort();
+
+        let (old, new) = (&previous.settings, &current.settings);
+        let tls_paths = |settings: &Settings| {
+            settings
+                .tls
+                .as_ref()
+                .map(|tls| (tls.cert_path.clone(), tls.key_path.clone()))
+        };
+        let restart_required = [
+            ("bind_address", old.bind_address != new.bind_address),
+            ("port", old.port != new.port),
+            ("tls", tls_paths(old) != tls_paths(new)),
+            ("log_directory", old.log_directory != new.log_directory),
+            ("instance_id", old.instance_id != new.instance_id),
+            ("audit_log_path", old.audit_log_path != new.audit_log_path),
+            ("otel_endpoint", old.otel_endpoint != new.otel_endpoint),
+            (
+                "max_request_body_bytes",
+                old.max_request_body_bytes != new.max_request_body_bytes,
+            ),
+            (
+                "shutdown_timeout_seconds",
+                old.shutdown_timeout_seconds != new.shutdown_timeout_seconds,
+            ),
+        ]
+        .into_iter()
+        .filter_map(|(name, changed)| changed.then_some(name))
+        .collect();
+
+        ReloadSummary {
+            added_issuers,
+            removed_issuers,
+            restart_required,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct ProviderState {
+    issuer: String,
+    jwks_uri: String,
+    key_count: usize,
+    /// When the configuration and keys of the provider were last fetched, in RFC 3339 format.
+    last_refreshed_at: String,
+    supported_algorithms: Vec<String>,
+    /// Claims the provider advertises, which may not include every claim it issues.
+    claims_supported: Vec<String>,
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct ProviderRefresh {
+    url: String,
+    refreshed: bool,
+    issuer: Option<String>,
+    error: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::error_chain;
+
+    #[test]
+    fn in_flight_requests_are_tracked_until_dropped() {
+        let requests = InFlightRequests::default();
+        let first = requests.track("first", "/exchange");
+        let second = requests.track("second", "/exchange/batch");
+        let listed = requests.list();
+        assert_eq!(
+            listed
+                .iter()
+                .map(|request| (request.request_id.as_str(), request.route))
+                .collect::<Vec<_>>(),
+            [("first", "/exchange"), ("second", "/exchange/batch")]
+        );
+
+        drop(first);
+        let listed = requests.list();
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].request_id, "second");
+        drop(second);
+        assert!(requests.list().is_empty());
+    }
+
+    #[test]
+    fn build_errors_mention_their_cause_once() {
+        let err = ContextBuildError::SiloUnreachable {
+            silo: "https://example.sys.oxide.computer".into(),
+            source: OxideError::NotConfigured,
+        };
+        assert_eq!(
+            error_chain(&err),
+            "Failed to reach the silo https://example.sys.oxide.computer: \
+             The Oxide token provider is not configured",
+        );
+    }
 }
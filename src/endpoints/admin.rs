@@ -0,0 +1,279 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use dropshot::{ClientErrorStatusCode, HttpError, HttpResponseOk, RequestContext, endpoint};
+use schemars::JsonSchema;
+use secrecy::ExposeSecret;
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+use crate::{
+    context::{Context, ProviderRefresh, ProviderState},
+    token::github::{GitHubAppMetadata, Installation, RateLimitState},
+    util::constant_time_eq,
+};
+
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct ProvidersRefreshed {
+    providers: Vec<ProviderRefresh>,
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct Providers {
+    providers: Vec<ProviderState>,
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct GitHubApps {
+    apps: Vec<GitHubAppMetadata>,
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct GitHubInstallations {
+    installations: Vec<Installation>,
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct GitHubRateLimits {
+    rate_limits: BTreeMap<String, RateLimitState>,
+}
+
+/// Ensure the request carries the admin token. Instances without an admin token pretend the admin
+/// endpoints don't exist.
+pub fn ensure_admin(rqctx: &RequestContext<Context>) -> Result<(), HttpError> {
+    let ctx = rqctx.context().state();
+    let Some(admin_token) = &ctx.settings.admin_token else {
+        return Err(HttpError::for_not_found(None, "Not found".to_string()));
+    };
+
+    let provided = rqctx
+        .request
+        .headers()
+        .get("authorization")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    match provided {
+        Some(provided)
+            if constant_time_eq(provided.as_bytes(), admin_token.expose_secret().as_bytes()) =>
+        {
+            Ok(())
+        }
+        _ => Err(HttpError::for_client_error(
+            None,
+            ClientErrorStatusCode::UNAUTHORIZED,
+            "Invalid admin token".to_string(),
+        )),
+    }
+}
+
+/// List the providers in use, with details about their configuration and keys.
+#[endpoint {
+    path = "/admin/providers",
+    method = GET,
+}]
+pub async fn providers(
+    rqctx: RequestContext<Context>,
+) -> Result<HttpResponseOk<Providers>, HttpError> {
+    ensure_admin(&rqctx)?;
+
+    Ok(HttpResponseOk(Providers {
+        providers: rqctx.context().state().provider_states(),
+    }))
+}
+
+/// Fetch the configuration and keys of all providers again, for example after a key rotation.
+#[endpoint {
+    path = "/admin/providers/refresh",
+    method = POST,
+}]
+pub async fn providers_refresh(
+    rqctx: RequestContext<Context>,
+) -> Result<HttpResponseOk<ProvidersRefreshed>, HttpError> {
+    ensure_admin(&rqctx)?;
+
+    Ok(HttpResponseOk(ProvidersRefreshed {
+        providers: rqctx.context().state().refresh_providers().await,
+    }))
+}
+
+/// Describe the configured GitHub Apps as reported by GitHub, to verify the credentials belong to
+/// the expected apps and that they have the expected permissions.
+#[endpoint {
+    path = "/admin/github/apps",
+    method = GET,
+}]
+pub async fn github_apps(
+    rqctx: RequestContext<Context>,
+) -> Result<HttpResponseOk<GitHubApps>, HttpError> {
+    ensure_admin(&rqctx)?;
+
+    let apps = rqctx
+        .context()
+        .state()
+        .github_tokens
+        .get_app_metadata()
+        .await
+        .map_err(|err| {
+            tracing::error!(?err, "Failed to retrieve the GitHub App metadata");
+            if err.safe_to_expose() {
+                HttpError::for_bad_request(
+                    None,
+                    format!("Failed to retrieve the GitHub App metadata: {err}"),
+                )
+            } else {
+                HttpError::for_internal_error(
+                    "Failed to retrieve the GitHub App metadata".to_string(),
+                )
+            }
+        })?;
+    Ok(HttpResponseOk(GitHubApps { apps }))
+}
+
+/// List the installations of the configured GitHub Apps, to verify they are installed on the
+/// expected organizations.
+#[endpoint {
+    path = "/admin/github/installations",
+    method = GET,
+}]
+pub async fn github_installations(
+    rqctx: RequestContext<Context>,
+) -> Result<HttpResponseOk<GitHubInstallations>, HttpError> {
+    ensure_admin(&rqctx)?;
+
+    let installations = rqctx
+        .context()
+        .state()
+        .github_tokens
+        .list_installations()
+        .await
+        .map_err(|err| {
+            tracing::error!(?err, "Failed to list the GitHub App installations");
+            if err.safe_to_expose() {
+                HttpError::for_bad_request(
+                    None,
+                    format!("Failed to list the GitHub App installations: {err}"),
+                )
+            } else {
+                HttpError::for_internal_error(
+                    "Failed to list the GitHub App installations".to_string(),
+                )
+            }
+        })?;
+    Ok(HttpResponseOk(GitHubInstallations { installations }))
+}
+
+/// Report the GitHub rate limits of the configured apps and their installations, as of the last
+/// request oidcx made with each of them.
+#[endpoint {
+    path = "/admin/github/rate-limits",
+    method = GET,
+}]
+pub async fn github_rate_limits(
+    rqctx: RequestContext<Context>,
+) -> Result<HttpResponseOk<GitHubRateLimits>, HttpError> {
+    ensure_admin(&rqctx)?;
+
+    let rate_limits = rqctx
+        .context()
+        .state()
+        .github_tokens
+        .rate_limits()
+        .map_err(|err| HttpError::for_bad_request(None, err.to_string()))?;
+    Ok(HttpResponseOk(GitHubRateLimits { rate_limits }))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::oidc::OidcProvider;
+    use crate::test_util::{MockResponse, MockServer, start_server, test_settings};
+
+    #[tokio::test]
+    async fn providers_are_refreshed_under_the_admin_path() {
+        let settings = test_settings("")
+            .with_admin_token("admin".to_string().into())
+            .build()
+            .unwrap();
+        let server = start_server(settings).await;
+        let client = reqwest::Client::new();
+        let url = format!("http://{}/admin/providers/refresh", server.local_addr());
+
+        let response = client.post(&url).send().await.unwrap();
+        assert_eq!(response.status(), 401);
+
+        let response = client.post(&url).bearer_auth("admin").send().await.unwrap();
+        assert_eq!(response.status(), 200);
+        let body: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(body, serde_json::json!({ "providers": [] }));
+
+        server.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn providers_are_listed_with_their_metadata() {
+        let provider = MockServer::start().await;
+        provider.respond(
+            "/.well-known/openid-configuration",
+            MockResponse::json(
+                200,
+                serde_json::json!({
+                    "issuer": provider.url(),
+                    "jwks_uri": format!("{}/jwks", provider.url()),
+                    "subject_types_supported": ["public"],
+                    "response_types_supported": ["id_token"],
+                    "claims_supported": ["sub", "repository"],
+                    "id_token_signing_alg_values_supported": ["RS256"],
+                }),
+            ),
+        );
+        provider.respond(
+            "/jwks",
+            MockResponse::json(200, serde_json::json!({ "keys": [] })),
+        );
+        let settings = test_settings("")
+            .with_admin_token("admin".to_string().into())
+            .with_provider(OidcProvider::new(format!(
+                "{}/.well-known/openid-configuration",
+                provider.url()
+            )))
+            .build()
+            .unwrap();
+        let server = start_server(settings).await;
+        let url = format!("http://{}/admin/providers", server.local_addr());
+
+        let response = reqwest::Client::new()
+            .get(&url)
+            .bearer_auth("admin")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+        let body: serde_json::Value = response.json().await.unwrap();
+        let state = &body["providers"][0];
+        assert_eq!(state["issuer"], provider.url());
+        assert_eq!(state["key_count"], 0);
+        assert_eq!(state["supported_algorithms"], serde_json::json!(["RS256"]));
+        assert_eq!(
+            state["claims_supported"],
+            serde_json::json!(["sub", "repository"])
+        );
+
+        server.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn admin_endpoints_are_hidden_without_an_admin_token() {
+        let server = start_server(test_settings("").build().unwrap()).await;
+        let url = format!("http://{}/admin/providers/refresh", server.local_addr());
+
+        let response = reqwest::Client::new()
+            .post(&url)
+            .bearer_auth("admin")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 404);
+
+        server.close().await.unwrap();
+    }
+}
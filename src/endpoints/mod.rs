@@ -0,0 +1,968 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use chrono::{DateTime, Utc};
+use dropshot::{HttpError, HttpResponseOk, RequestContext, UntypedBody, endpoint};
+use futures_util::future::join_all;
+use schemars::{JsonSchema, r#gen::SchemaSettings};
+use serde::{Deserialize, Serialize, Serializer, de::DeserializeOwned};
+use std::fmt;
+use tracing::Instrument;
+use zeroize::Zeroize;
+
+use crate::token::aws::AwsTokenRequest;
+use crate::token::gcp::GcpTokenRequest;
+use crate::token::github::{GitHubPackagesTokenRequest, GitHubTokenRequest};
+use crate::token::oxide::OxideTokenRequest;
+use crate::{
+    audit::{AuditEvent, AuditResult},
+    context::{Context, ContextInner},
+    oidc::{Claims, IssuerClaim, OidcError},
+    server::api,
+};
+
+pub mod admin;
+pub mod policy;
+
+// An Oxide access token with a fixed expiration time.
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct Token {
+    pub access_token: SecretToken,
+    /// Additional credentials for services that need more than an access token, like AWS.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub extra: Option<SecretExtra>,
+    /// When the token expires, if the service reports it. Not returned to callers, only used to
+    /// know how long the token can be cached.
+    #[serde(skip)]
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+/// A generated credential, overwritten in memory once dropped so that it doesn't linger on the
+/// heap after being sent to the caller. It's redacted when formatted, and it intentionally doesn't
+/// implement `Clone`: copies can only be made explicitly, with [`SecretToken::expose`].
+#[derive(JsonSchema)]
+#[schemars(transparent)]
+pub struct SecretToken(String);
+
+impl SecretToken {
+    pub fn new(token: String) -> Self {
+        Self(token)
+    }
+
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Zeroize for SecretToken {
+    fn zeroize(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+impl Drop for SecretToken {
+    fn drop(&mut self) {
+        self.zeroize();
+    }
+}
+
+impl Serialize for SecretToken {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.0)
+    }
+}
+
+impl fmt::Debug for SecretToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("[REDACTED]")
+    }
+}
+
+impl fmt::Display for SecretToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("[REDACTED]")
+    }
+}
+
+/// Additional credentials returned with a token, like the AWS secret access key. Overwritten in
+/// memory once dropped and redacted when formatted, like [`SecretToken`].
+#[derive(JsonSchema)]
+#[schemars(transparent)]
+pub struct SecretExtra(serde_json::Value);
+
+impl SecretExtra {
+    pub fn new(extra: serde_json::Value) -> Self {
+        Self(extra)
+    }
+
+    pub fn expose(&self) -> &serde_json::Value {
+        &self.0
+    }
+}
+
+impl Zeroize for SecretExtra {
+    fn zeroize(&mut self) {
+        fn zeroize_value(value: &mut serde_json::Value) {
+            match value {
+                serde_json::Value::String(string) => string.zeroize(),
+                serde_json::Value::Array(values) => values.iter_mut().for_each(zeroize_value),
+                // Only the values are overwritten, the keys are field names.
+                serde_json::Value::Object(map) => map.values_mut().for_each(zeroize_value),
+                serde_json::Value::Null
+                | serde_json::Value::Bool(_)
+                | serde_json::Value::Number(_) => {}
+            }
+        }
+        zeroize_value(&mut self.0);
+    }
+}
+
+impl Drop for SecretExtra {
+    fn drop(&mut self) {
+        self.zeroize();
+    }
+}
+
+impl Serialize for SecretExtra {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.0.serialize(serializer)
+    }
+}
+
+impl fmt::Debug for SecretExtra {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("[REDACTED]")
+    }
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct ExchangeBody {
+    caller_identity: String,
+    /// Authorize the request without generating a token, if the instance allows it.
+    #[serde(default)]
+    simulate: bool,
+    #[serde(flatten)]
+    request: TokenRequest,
+}
+
+/// The token to generate. Each service is a variant handled by its own store in the context (like
+/// `OxideTokens`), rather than a generic variant dispatching by name: that way policies match on a
+/// class per service, and the OpenAPI description documents the parameters of each service.
+#[derive(Clone, Debug, Deserialize, JsonSchema, Hash, PartialEq, Eq)]
+#[serde(tag = "service", rename_all = "lowercase")]
+pub enum TokenRequest {
+    Oxide(OxideTokenRequest),
+    GitHub(GitHubTokenRequest),
+    #[serde(rename = "github-packages")]
+    GitHubPackages(GitHubPackagesTokenRequest),
+    Aws(AwsTokenRequest),
+    Gcp(GcpTokenRequest),
+}
+
+impl TokenRequest {
+    /// Value of the `service` field for this request.
+    pub fn service(&self) -> &'static str {
+        match self {
+            TokenRequest::Oxide(_) => "oxide",
+            TokenRequest::GitHub(_) => "github",
+            TokenRequest::GitHubPackages(_) => "github-packages",
+            TokenRequest::Aws(_) => "aws",
+            TokenRequest::Gcp(_) => "gcp",
+        }
+    }
+
+    /// Values of the `service` field accepted by the exchange endpoint.
+    pub fn supported_services() -> &'static [&'static str] {
+        &["oxide", "github", "github-packages", "aws", "gcp"]
+    }
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct Capabilities {
+    services: Vec<String>,
+    issuers: Vec<String>,
+}
+
+// JSON:API clients send JSON bodies with their own media type, which Dropshot would reject if we
+// used a TypedBody. Both are parsed the same way.
+static JSON_CONTENT_TYPES: &[&str] = &["application/json", "application/vnd.api+json"];
+
+fn parse_json_body<T: DeserializeOwned>(
+    rqctx: &RequestContext<Context>,
+    body: UntypedBody,
+) -> Result<T, HttpError> {
+    // Like Dropshot, treat requests without a content type as JSON.
+    if let Some(content_type) = rqctx.request.headers().get("content-type") {
+        let content_type = content_type
+            .to_str()
+            .ok()
+            .and_then(|value| value.split(';').next())
+            .map(|value| value.trim().to_ascii_lowercase())
+            .unwrap_or_default();
+        if !JSON_CONTENT_TYPES.contains(&content_type.as_str()) {
+            return Err(HttpError::for_bad_request(
+                None,
+                format!("Unsupported content type {content_type}"),
+            ));
+        }
+    }
+
+    serde_json::from_slice(body.as_bytes()).map_err(|err| {
+        HttpError::for_bad_request(None, format!("Unable to parse JSON body: {err}"))
+    })
+}
+
+/// Deserialize a body parsed by [`parse_json_body`], after checking it with `validate`. Serde's
+/// errors for the internally tagged token requests expose its internals, so the most common
+/// mistakes are caught beforehand and reported in plain words.
+fn parse_exchange_body<T: DeserializeOwned>(
+    body: serde_json::Value,
+    validate: impl FnOnce(&serde_json::Value) -> Result<(), String>,
+) -> Result<T, HttpError> {
+    validate(&body).map_err(|message| HttpError::for_bad_request(None, message))?;
+    serde_json::from_value(body)
+        .map_err(|err| HttpError::for_bad_request(None, format!("Invalid request: {err}")))
+}
+
+/// Check the fields of a token request, returning an error message meant for the caller.
+fn validate_token_request(request: &serde_json::Value) -> Result<(), String> {
+    let Some(request) = request.as_object() else {
+        return Err("The request must be a JSON object".to_string());
+    };
+    let service = match request.get("service") {
+        Some(serde_json::Value::String(service)) => service.as_str(),
+        Some(_) => return Err("Field 'service' must be a string".to_string()),
+        None => return Err("Missing required field 'service'".to_string()),
+    };
+    let services = TokenRequest::supported_services();
+    if !services.contains(&service) {
+        return Err(format!(
+            "Unknown service '{service}': valid values are {}",
+            services
+                .iter()
+                .map(|service| format!("'{service}'"))
+                .collect::<Vec<_>>()
+                .join(", ")
+        ));
+    }
+    if service == "oxide"
+        && let Some(duration) = request.get("duration")
+        && !duration.is_u64()
+    {
+        return Err("Field 'duration' must be a non-negative integer".to_string());
+    }
+    Ok(())
+}
+
+/// Create the span covering an exchange request.
+fn exchange_span(rqctx: &RequestContext<Context>, route: &'static str) -> tracing::Span {
+    // Dropshot returns its request ID to the client in the x-request-id response header, so
+    // attaching it to the span lets log lines be matched with what clients report.
+    tracing::info_span!(
+        "exchange",
+        http.method = "POST",
+        http.route = route,
+        http.status_code = tracing::field::Empty,
+        request_id = %rqctx.request_id,
+        client_request_id = rqctx
+            .request
+            .headers()
+            .get("x-request-id")
+            .and_then(|value| value.to_str().ok()),
+    )
+}
+
+/// Exchange an OIDC provider identity token for an Oxide access token.
+#[endpoint {
+    path = "/exchange",
+    method = POST,
+}]
+pub async fn exchange(
+    rqctx: RequestContext<Context>,
+    body: UntypedBody,
+) -> Result<HttpResponseOk<Token>, HttpError> {
+    let span = exchange_span(&rqctx, "/exchange");
+    let _in_flight = rqctx
+        .context()
+        .in_flight()
+        .track(&rqctx.request_id, "/exchange");
+    let body: ExchangeBody =
+        parse_exchange_body(parse_json_body(&rqctx, body)?, validate_token_request)?;
+    let result = exchange_token(&rqctx.context().state(), &rqctx.request_id, body)
+        .instrument(span.clone())
+        .await;
+    span.record(
+        "http.status_code",
+        match &result {
+            Ok(_) => 200,
+            Err(err) => err.status_code.as_u16(),
+        },
+    );
+    result.map(HttpResponseOk)
+}
+
+async fn exchange_token(
+    ctx: &ContextInner,
+    request_id: &str,
+    body: ExchangeBody,
+) -> Result<Token, HttpError> {
+    if body.simulate && !ctx.settings.enable_simulate_mode {
+        return Err(HttpError::for_bad_request(
+            None,
+            "Simulate mode is not enabled on this instance".to_string(),
+        ));
+    }
+
+    let audit = |claims: Option<&Claims>, result: AuditResult| {
+        AuditEvent::new(request_id, &body.request, claims, result).emit()
+    };
+
+    // Simulated exchanges don't record the token, so it can still be exchanged afterwards.
+    let claims = validate_caller_identity(ctx, &body.caller_identity, !body.simulate)
+        .inspect_err(|err| audit(None, AuditResult::denied(err)))?;
+    authorize(ctx, &claims, &body.request)
+        .await
+        .inspect_err(|err| audit(Some(&claims), AuditResult::denied(err)))?;
+
+    if body.simulate {
+        tracing::warn!("Simulate mode was used, skipping token generation");
+        return Ok(Token {
+            access_token: SecretToken::new("DRY_RUN".to_string()),
+            extra: None,
+            expires_at: None,
+        });
+    }
+
+    let result = generate_cached_token(ctx, &claims, &body.request, &body.caller_identity).await;
+    audit(
+        Some(&claims),
+        match &result {
+            Ok(_) => AuditResult::Granted,
+            Err(err) => AuditResult::error(err),
+        },
+    );
+    result
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct BatchExchangeBody {
+    caller_identity: String,
+    requests: Vec<TokenRequest>,
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct BatchTokenResult {
+    service: &'static str,
+    /// The token, or why it couldn't be generated.
+    result: Result<Token, String>,
+}
+
+/// Exchange an OIDC provider identity token for multiple tokens at once. The identity token is
+/// validated once, and each request is authorized and generated independently: the failure of
+/// one request doesn't fail the others.
+#[endpoint {
+    path = "/exchange/batch",
+    method = POST,
+}]
+pub async fn exchange_batch(
+    rqctx: RequestContext<Context>,
+    body: UntypedBody,
+) -> Result<HttpResponseOk<Vec<BatchTokenResult>>, HttpError> {
+    let span = exchange_span(&rqctx, "/exchange/batch");
+    let _in_flight = rqctx
+        .context()
+        .in_flight()
+        .track(&rqctx.request_id, "/exchange/batch");
+    let body: BatchExchangeBody = parse_exchange_body(parse_json_body(&rqctx, body)?, |body| {
+        let requests = body
+            .get("requests")
+            .and_then(|requests| requests.as_array());
+        for (index, request) in requests.into_iter().flatten().enumerate() {
+            validate_token_request(request)
+                .map_err(|message| format!("Request {index}: {message}"))?;
+        }
+        Ok(())
+    })?;
+    let ctx = rqctx.context().state();
+    if body.requests.len() > ctx.settings.max_batch_size {
+        return Err(HttpError::for_bad_request(
+            None,
+            format!(
+                "A batch can contain at most {} requests",
+                ctx.settings.max_batch_size
+            ),
+        ));
+    }
+    let result = exchange_tokens(&ctx, &rqctx.request_id, body)
+        .instrument(span.clone())
+        .await;
+    span.record(
+        "http.status_code",
+        match &result {
+            Ok(_) => 200,
+            Err(err) => err.status_code.as_u16(),
+        },
+    );
+    result.map(HttpResponseOk)
+}
+
+async fn exchange_tokens(
+    ctx: &ContextInner,
+    request_id: &str,
+    body: BatchExchangeBody,
+) -> Result<Vec<BatchTokenResult>, HttpError> {
+    // Every request of the batch gets its own audit event.
+    let claims = validate_caller_identity(ctx, &body.caller_identity, true).inspect_err(|err| {
+        for request in &body.requests {
+            AuditEvent::new(request_id, request, None, AuditResult::denied(err)).emit();
+        }
+    })?;
+    let audit = |request: &TokenRequest, result: AuditResult| {
+        AuditEvent::new(request_id, request, Some(&claims), result).emit()
+    };
+
+    let mut authorized = Vec::new();
+    for request in &body.requests {
+        let result = authorize(ctx, &claims, request).await;
+        if let Err(err) = &result {
+            audit(request, AuditResult::denied(err));
+        }
+        authorized.push(result);
+    }
+
+    let caller_identity = body.caller_identity.as_str();
+    // Borrowed, as the futures below can't each take the claims.
+    let claims = &claims;
+    let results = body
+        .requests
+        .iter()
+        .zip(authorized)
+        .map(|(request, authorized)| async move {
+            let result = match authorized {
+                Ok(()) => {
+                    let result = generate_cached_token(ctx, claims, request, caller_identity).await;
+                    audit(
+                        request,
+                        match &result {
+                            Ok(_) => AuditResult::Granted,
+                            Err(err) => AuditResult::error(err),
+                        },
+                    );
+                    result
+                }
+                Err(err) => Err(err),
+            };
+            BatchTokenResult {
+                service: request.service(),
+                result: result.map_err(|err| err.external_message),
+            }
+        });
+    Ok(join_all(results).await)
+}
+
+/// Validate the identity token against the provider that issued it, returning its claims. Tokens
+/// that will be `exchanged` are also recorded, to reject replays if enabled.
+fn validate_caller_identity(
+    ctx: &ContextInner,
+    caller_identity: &str,
+    exchanged: bool,
+) -> Result<Claims, HttpError> {
+    let issuer = jsonwebtoken::dangerous::insecure_decode::<IssuerClaim>(caller_identity)
+        .map_err(|err| {
+            tracing::info!(?err, "Failed to decode token");
+            HttpError::for_bad_request(None, "Invalid token".to_string())
+        })?
+        .claims
+        .iss;
+
+    let provider = ctx
+        .providers
+        .get(&issuer)
+        .ok_or_else(|| {
+            tracing::info!(issuer, "Provider not found for issuer");
+            HttpError::for_bad_request(None, "Unsupported issuer".to_string())
+        })?
+        .clone();
+
+    let claims = provider
+        .read()
+        .unwrap()
+        .config
+        .validate(
+            &ctx.settings,
+            caller_identity,
+            exchanged.then_some(&ctx.nonces),
+        )
+        .map_err(|err| {
+            tracing::info!(?err, reason = %err, "Failed to validate token");
+            match err {
+                // Callers can't tell why a token was replayed without the reason.
+                OidcError::MissingJti | OidcError::MissingExp | OidcError::TokenReplayed => {
+                    HttpError::for_bad_request(None, format!("Token validation failed: {err}"))
+                }
+                _ => HttpError::for_bad_request(None, "Token validation failed".to_string()),
+            }
+        })?;
+    tracing::debug!(claims = %serde_json::json!(claims), "Validated the token");
+    Ok(claims)
+}
+
+async fn authorize(
+    ctx: &ContextInner,
+    claims: &Claims,
+    request: &TokenRequest,
+) -> Result<(), HttpError> {
+    if let Err(err) = ctx
+        .policy
+        .ensure_allowed(claims, request)
+        .instrument(tracing::info_span!("policy.evaluate"))
+        .await
+    {
+        tracing::info!(?err, "Failed to match the token against the policy");
+        let mut message = format!("Token doesn't match the policy: {err}");
+        if ctx.settings.verbose_policy_errors {
+            let explanation = ctx.policy.explain(claims, request).await;
+            tracing::debug!(?explanation, "Explained the policy evaluation");
+            message = format!("{message} ({})", explanation.join("; "));
+        }
+        return Err(HttpError::for_bad_request(None, message));
+    }
+    Ok(())
+}
+
+/// Generate the token, reusing the one generated for a previous identical request with the same
+/// identity token if the response cache is enabled.
+async fn generate_cached_token(
+    ctx: &ContextInner,
+    claims: &Claims,
+    request: &TokenRequest,
+    caller_identity: &str,
+) -> Result<Token, HttpError> {
+    let Some(cache) = &ctx.response_cache else {
+        return generate_token(ctx, request, caller_identity).await;
+    };
+    if let Some(token) = cache.get(claims, request) {
+        tracing::info!("Returning a cached token");
+        return Ok(token);
+    }
+    let token = generate_token(ctx, request, caller_identity).await?;
+    cache.insert(claims, request, &token);
+    Ok(token)
+}
+
+async fn generate_token(
+    ctx: &ContextInner,
+    request: &TokenRequest,
+    caller_identity: &str,
+) -> Result<Token, HttpError> {
+    Ok(match request {
+        TokenRequest::Oxide(oxide) => ctx.oxide_tokens.get(oxide).await.map_err(|err| {
+            tracing::error!(?err, "Failed to generate token");
+            if err.safe_to_expose() {
+                HttpError::for_bad_request(None, format!("Failed to generate token: {err}"))
+            } else {
+                HttpError::for_internal_error("Failed to generate token".to_string())
+            }
+        })?,
+        TokenRequest::GitHub(github) => ctx.github_tokens.get(github).await.map_err(|err| {
+            tracing::error!(?err, "Failed to generate token");
+            if err.safe_to_expose() {
+                HttpError::for_bad_request(None, format!("Failed to generate token: {err}"))
+            } else {
+                HttpError::for_internal_error("Failed to generate token".to_string())
+            }
+        })?,
+        TokenRequest::GitHubPackages(packages) => {
+            let token = ctx
+                .github_tokens
+                .get_packages_token(packages)
+                .await
+                .map_err(|err| {
+                    tracing::error!(?err, "Failed to generate token");
+                    if err.safe_to_expose() {
+                        HttpError::for_bad_request(None, format!("Failed to generate token: {err}"))
+                    } else {
+                        HttpError::for_internal_error("Failed to generate token".to_string())
+                    }
+                })?;
+            Token {
+                access_token: token.basic_auth(),
+                extra: None,
+                expires_at: token.expires_at,
+            }
+        }
+        TokenRequest::Aws(aws) => {
+            ctx.aws_tokens
+                .get(aws, caller_identity)
+                .await
+                .map_err(|err| {
+                    tracing::error!(?err, "Failed to generate token");
+                    if err.safe_to_expose() {
+                        HttpError::for_bad_request(None, format!("Failed to generate token: {err}"))
+                    } else {
+                        HttpError::for_internal_error("Failed to generate token".to_string())
+                    }
+                })?
+        }
+        TokenRequest::Gcp(gcp) => ctx.gcp_tokens.get(gcp).await.map_err(|err| {
+            tracing::error!(?err, "Failed to generate token");
+            if err.safe_to_expose() {
+                HttpError::for_bad_request(None, format!("Failed to generate token: {err}"))
+            } else {
+                HttpError::for_internal_error("Failed to generate token".to_string())
+            }
+        })?,
+    })
+}
+
+/// List the services tokens can be requested for, and the issuers of accepted identity tokens.
+#[endpoint {
+    path = "/exchange/capabilities",
+    method = GET,
+}]
+pub async fn capabilities(
+    rqctx: RequestContext<Context>,
+) -> Result<HttpResponseOk<Capabilities>, HttpError> {
+    let mut issuers = rqctx
+        .context()
+        .state()
+        .providers
+        .keys()
+        .cloned()
+        .collect::<Vec<_>>();
+    issuers.sort();
+
+    Ok(HttpResponseOk(Capabilities {
+        services: TokenRequest::supported_services()
+            .iter()
+            .map(|service| service.to_string())
+            .collect(),
+        issuers,
+    }))
+}
+
+/// Return the OpenAPI description of the service, to generate clients from. The description is
+/// generated once when the context is built.
+#[endpoint {
+    path = "/openapi.json",
+    method = GET,
+}]
+pub async fn openapi(
+    rqctx: RequestContext<Context>,
+) -> Result<HttpResponseOk<serde_json::Value>, HttpError> {
+    let ctx = rqctx.context().state();
+    if !ctx.settings.expose_openapi {
+        return Err(HttpError::for_not_found(
+            None,
+            "The OpenAPI description is not exposed".to_string(),
+        ));
+    }
+
+    Ok(HttpResponseOk(ctx.openapi.as_ref().clone()))
+}
+
+/// Generate the OpenAPI description of all the endpoints.
+pub fn openapi_description() -> Result<serde_json::Value, serde_json::Error> {
+    let mut description = api()
+        .openapi(
+            "oidcx",
+            env!("CARGO_PKG_VERSION")
+                .parse()
+                .expect("the crate version is valid semver"),
+        )
+        .json()?;
+    document_json_bodies(&mut description)?;
+    Ok(description)
+}
+
+/// Dropshot describes the body of endpoints taking an [`UntypedBody`] as arbitrary bytes, so add
+/// the schemas of the bodies parsed by [`parse_json_body`] to the description.
+fn document_json_bodies(description: &mut serde_json::Value) -> Result<(), serde_json::Error> {
+    let mut generator = SchemaSettings::openapi3().into_generator();
+    let bodies = [
+        ("/exchange", generator.subschema_for::<ExchangeBody>()),
+        (
+            "/exchange/batch",
+            generator.subschema_for::<BatchExchangeBody>(),
+        ),
+    ];
+    for (path, schema) in bodies {
+        let schema = serde_json::to_value(schema)?;
+        description["paths"][path]["post"]["requestBody"]["content"] = JSON_CONTENT_TYPES
+            .iter()
+            .map(|content_type| {
+                (
+                    content_type.to_string(),
+                    serde_json::json!({ "schema": schema }),
+                )
+            })
+            .collect::<serde_json::Map<_, _>>()
+            .into();
+    }
+    for (name, schema) in generator.take_definitions() {
+        description["components"]["schemas"][name] = serde_json::to_value(schema)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{start_server, test_settings};
+    use crate::token::github::RepositorySelection;
+    use crate::token::oxide::DurationUnit;
+
+    #[test]
+    fn openapi_description_documents_exchange_bodies() {
+        let description = openapi_description().unwrap();
+        for (path, schema) in [
+            ("/exchange", "ExchangeBody"),
+            ("/exchange/batch", "BatchExchangeBody"),
+        ] {
+            let content = &description["paths"][path]["post"]["requestBody"]["content"];
+            for content_type in JSON_CONTENT_TYPES {
+                assert_eq!(
+                    content[*content_type]["schema"]["$ref"],
+                    format!("#/components/schemas/{schema}"),
+                );
+            }
+            assert!(description["components"]["schemas"][schema].is_object());
+        }
+        assert!(description["components"]["schemas"]["TokenRequest"].is_object());
+    }
+
+    #[test]
+    fn token_requests_are_parsed_for_every_service() {
+        let cases = [
+            (
+                serde_json::json!({
+                    "service": "oxide",
+                    "silo": "https://example.sys.oxide.computer",
+                    "duration": 2,
+                    "duration_unit": "hours",
+                }),
+                TokenRequest::Oxide(OxideTokenRequest {
+                    silo: "https://example.sys.oxide.computer".into(),
+                    duration: 2,
+                    duration_unit: Some(DurationUnit::Hours),
+                }),
+            ),
+            (
+                serde_json::json!({
+                    "service": "github",
+                    "repositories": ["oxidecomputer/oidcx"],
+                    "permissions": ["contents:read"],
+                }),
+                TokenRequest::GitHub(GitHubTokenRequest {
+                    repositories: vec!["oxidecomputer/oidcx".into()],
+                    permissions: vec!["contents:read".into()],
+                    repository_selection: None,
+                    owner: None,
+                }),
+            ),
+            (
+                serde_json::json!({
+                    "service": "github",
+                    "permissions": ["contents:read"],
+                    "repository_selection": "all",
+                    "owner": "oxidecomputer",
+                }),
+                TokenRequest::GitHub(GitHubTokenRequest {
+                    repositories: Vec::new(),
+                    permissions: vec!["contents:read".into()],
+                    repository_selection: Some(RepositorySelection::All),
+                    owner: Some("oxidecomputer".into()),
+                }),
+            ),
+            (
+                serde_json::json!({
+                    "service": "github-packages",
+                    "repositories": ["oxidecomputer/oidcx"],
+                }),
+                TokenRequest::GitHubPackages(GitHubPackagesTokenRequest {
+                    repositories: vec!["oxidecomputer/oidcx".into()],
+                    write: false,
+                }),
+            ),
+            (
+                serde_json::json!({
+                    "service": "aws",
+                    "role_alias": "deploy",
+                    "session_duration_seconds": 900,
+                }),
+                TokenRequest::Aws(AwsTokenRequest {
+                    role_alias: "deploy".into(),
+                    session_duration_seconds: Some(900),
+                }),
+            ),
+            (
+                serde_json::json!({
+                    "service": "gcp",
+                    "service_account_email": "deploy@example.iam.gserviceaccount.com",
+                    "scopes": ["https://www.googleapis.com/auth/cloud-platform"],
+                }),
+                TokenRequest::Gcp(GcpTokenRequest {
+                    service_account_email: "deploy@example.iam.gserviceaccount.com".into(),
+                    scopes: vec!["https://www.googleapis.com/auth/cloud-platform".into()],
+                }),
+            ),
+        ];
+        for (json, expected) in cases {
+            let request = serde_json::from_value::<TokenRequest>(json.clone()).unwrap();
+            assert_eq!(request, expected, "{json}");
+        }
+    }
+
+    #[test]
+    fn exchange_bodies_flatten_the_token_request() {
+        let body = serde_json::from_value::<ExchangeBody>(serde_json::json!({
+            "caller_identity": "identity-token",
+            "service": "aws",
+            "role_alias": "deploy",
+        }))
+        .unwrap();
+        assert_eq!(body.caller_identity, "identity-token");
+        assert!(!body.simulate);
+        assert_eq!(
+            body.request,
+            TokenRequest::Aws(AwsTokenRequest {
+                role_alias: "deploy".into(),
+                session_duration_seconds: None,
+            })
+        );
+    }
+
+    #[test]
+    fn tokens_are_serialized_with_their_extra_credentials() {
+        let token = Token {
+            access_token: SecretToken::new("session".into()),
+            extra: Some(SecretExtra::new(
+                serde_json::json!({ "access_key_id": "ASIA" }),
+            )),
+            expires_at: Some(Utc::now()),
+        };
+        assert_eq!(
+            serde_json::to_value(&token).unwrap(),
+            serde_json::json!({
+                "access_token": "session",
+                "extra": { "access_key_id": "ASIA" },
+            })
+        );
+
+        let token = Token {
+            access_token: SecretToken::new("token".into()),
+            extra: None,
+            expires_at: None,
+        };
+        assert_eq!(
+            serde_json::to_value(&token).unwrap(),
+            serde_json::json!({ "access_token": "token" })
+        );
+    }
+
+    #[test]
+    fn unknown_services_are_rejected() {
+        let err = serde_json::from_value::<TokenRequest>(serde_json::json!({
+            "service": "custom",
+            "provider_name": "vault",
+            "params": {},
+        }))
+        .unwrap_err();
+        assert!(
+            err.to_string().contains("unknown variant `custom`"),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn extra_credentials_are_zeroized_and_redacted() {
+        let mut extra = SecretExtra::new(serde_json::json!({
+            "secret_access_key": "secret",
+            "nested": [{"session_token": "token"}],
+            "expiration": 1791985500,
+        }));
+        assert_eq!(format!("{extra:?}"), "[REDACTED]");
+
+        extra.zeroize();
+        assert_eq!(
+            *extra.expose(),
+            serde_json::json!({
+                "secret_access_key": "",
+                "nested": [{"session_token": ""}],
+                "expiration": 1791985500,
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn openapi_description_is_served_from_the_context() {
+        let server = start_server(test_settings("").build().unwrap()).await;
+        let url = format!("http://{}/openapi.json", server.local_addr());
+
+        let response = reqwest::get(&url).await.unwrap();
+        assert_eq!(response.status(), 200);
+        let description: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(description, *server.app_private().state().openapi);
+
+        server.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn oversized_batches_are_rejected() {
+        let settings = test_settings("").with_max_batch_size(1).build().unwrap();
+        let server = start_server(settings).await;
+        let url = format!("http://{}/exchange/batch", server.local_addr());
+        let request = serde_json::json!({
+            "service": "oxide",
+            "silo": "https://example.sys.oxide.computer",
+            "duration": 3600,
+        });
+
+        let response = reqwest::Client::new()
+            .post(&url)
+            .json(&serde_json::json!({
+                "caller_identity": "not-a-jwt",
+                "requests": [request, request],
+            }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 400);
+        let body = response.text().await.unwrap();
+        assert!(body.contains("at most 1 requests"), "{body}");
+
+        server.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn denials_are_only_explained_when_verbose() {
+        let claims: Claims = serde_json::from_value(serde_json::json!({ "sub": "oidcx" })).unwrap();
+        let request: TokenRequest = serde_json::from_value(serde_json::json!({
+            "service": "oxide",
+            "silo": "https://example.sys.oxide.computer",
+            "duration": 3600,
+        }))
+        .unwrap();
+        let denial = async |verbose: bool| {
+            let settings = test_settings("allow_request(_claims, _request) if false;")
+                .with_verbose_policy_errors(verbose)
+                .build()
+                .unwrap();
+            let ctx = Context::new(settings).await.unwrap().state();
+            authorize(&ctx, &claims, &request)
+                .await
+                .unwrap_err()
+                .external_message
+        };
+
+        let terse = denial(false).await;
+        assert!(
+            terse.starts_with("Token doesn't match the policy: "),
+            "{terse}"
+        );
+        assert!(!terse.contains('('), "{terse}");
+        let verbose = denial(true).await;
+        assert!(verbose.starts_with(&format!("{terse} (")), "{verbose}");
+    }
+}
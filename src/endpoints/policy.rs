@@ -0,0 +1,94 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use dropshot::{HttpError, HttpResponseOk, RequestContext, UntypedBody, endpoint};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use super::{TokenRequest, admin::ensure_admin, parse_json_body};
+use crate::{context::Context, oidc::Claims, policy::Policy};
+
+#[derive(Debug, Deserialize)]
+pub struct PolicyTestBody {
+    claims: Claims,
+    request: TokenRequest,
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct PolicyTestResult {
+    allowed: bool,
+    reason: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PolicyValidateBody {
+    content: String,
+}
+
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct PolicyValidation {
+    valid: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// Evaluate the policy against the provided claims, without validating any JWT or generating a
+/// token. Anyone able to call this endpoint can probe the policy, so it's disabled by default.
+#[endpoint {
+    path = "/policy/test",
+    method = POST,
+}]
+pub async fn policy_test(
+    rqctx: RequestContext<Context>,
+    body: UntypedBody,
+) -> Result<HttpResponseOk<PolicyTestResult>, HttpError> {
+    let ctx = rqctx.context().state();
+    if !ctx.settings.enable_policy_test_endpoint {
+        return Err(HttpError::for_not_found(
+            None,
+            "The policy test endpoint is disabled".to_string(),
+        ));
+    }
+
+    let body: PolicyTestBody = parse_json_body(&rqctx, body)?;
+    Ok(HttpResponseOk(
+        match ctx.policy.ensure_allowed(&body.claims, &body.request).await {
+            Ok(()) => PolicyTestResult {
+                allowed: true,
+                reason: "The request matches the authorization policy".to_string(),
+            },
+            Err(err) => PolicyTestResult {
+                allowed: false,
+                reason: err.to_string(),
+            },
+        },
+    ))
+}
+
+/// Check whether a policy loads successfully, before deploying it. The policy is loaded in a
+/// separate Oso instance, and the policy in use is left untouched.
+#[endpoint {
+    path = "/policy/validate",
+    method = POST,
+}]
+pub async fn policy_validate(
+    rqctx: RequestContext<Context>,
+    body: UntypedBody,
+) -> Result<HttpResponseOk<PolicyValidation>, HttpError> {
+    ensure_admin(&rqctx)?;
+
+    let body: PolicyValidateBody = parse_json_body(&rqctx, body)?;
+    Ok(HttpResponseOk(
+        match Policy::validate_source(&body.content) {
+            Ok(()) => PolicyValidation {
+                valid: true,
+                error: None,
+            },
+            Err(err) => PolicyValidation {
+                valid: false,
+                error: Some(err.to_string()),
+            },
+        },
+    ))
+}
@@ -0,0 +1,146 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use opentelemetry_otlp::{ExporterBuildError, WithExportConfig};
+use opentelemetry_sdk::{Resource, trace::SdkTracerProvider};
+use regex::Regex;
+use serde_json::{Map, Value};
+use std::fmt::{self, Write};
+use std::sync::LazyLock;
+use tracing::{Event, Subscriber};
+use tracing_subscriber::{
+    fmt::{
+        FmtContext, FormatEvent, FormatFields,
+        format::{Format, Json, Writer},
+    },
+    registry::LookupSpan,
+};
+
+static PRIVATE_KEY_PATTERN: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"(?s)-----BEGIN [A-Z ]*PRIVATE KEY-----.*?-----END [A-Z ]*PRIVATE KEY-----")
+        .expect("the private key pattern is valid")
+});
+
+/// JSON event formatter adding an `instance_id` field to every event, to tell apart the logs of
+/// multiple instances of oidcx running at the same time.
+pub struct InstanceIdFormat {
+    instance_id: String,
+    inner: Format<Json>,
+}
+
+impl InstanceIdFormat {
+    pub fn new(instance_id: Option<String>) -> Self {
+        Self {
+            instance_id: instance_id
+                .unwrap_or_else(|| gethostname::gethostname().to_string_lossy().into_owned()),
+            inner: tracing_subscriber::fmt::format()
+                .with_file(false)
+                .with_line_number(false)
+                .json(),
+        }
+    }
+}
+
+impl<S, N> FormatEvent<S, N> for InstanceIdFormat
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        let mut buffer = String::new();
+        self.inner
+            .format_event(ctx, Writer::new(&mut buffer), event)?;
+        // Private keys should never be logged, but if one ends up in an error message or a debug
+        // representation, make sure it doesn't reach the log files.
+        if buffer.contains("PRIVATE KEY-----") {
+            buffer = PRIVATE_KEY_PATTERN
+                .replace_all(&buffer, "[REDACTED PRIVATE KEY]")
+                .into_owned();
+        }
+
+        match serde_json::from_str::<Map<String, Value>>(&buffer) {
+            Ok(mut fields) => {
+                fields.insert(
+                    "instance_id".to_string(),
+                    Value::String(self.instance_id.clone()),
+                );
+                writeln!(writer, "{}", Value::Object(fields))
+            }
+            // Never drop an event just because it couldn't be annotated.
+            Err(_) => writer.write_str(&buffer),
+        }
+    }
+}
+
+/// Build a tracer provider exporting spans to the OpenTelemetry collector at the endpoint, over
+/// gRPC. Spans are exported in batches from a background thread.
+pub fn otel_tracer_provider(endpoint: &str) -> Result<SdkTracerProvider, ExporterBuildError> {
+    let exporter = opentelemetry_otlp::SpanExporter::builder()
+        .with_tonic()
+        .with_endpoint(endpoint)
+        .build()?;
+    Ok(SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(Resource::builder().with_service_name("oidcx").build())
+        .build())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::GITHUB_APP_PRIVATE_KEY;
+    use std::sync::{Arc, Mutex};
+    use tracing_subscriber::{fmt::format::JsonFields, layer::SubscriberExt};
+
+    /// Format the events logged by `log` like oidcx does, returning the output.
+    fn capture(log: impl FnOnce()) -> String {
+        let output = Arc::new(Mutex::new(Vec::new()));
+        let writer = output.clone();
+        let subscriber = tracing_subscriber::registry().with(
+            tracing_subscriber::fmt::layer()
+                .fmt_fields(JsonFields::new())
+                .event_format(InstanceIdFormat::new(Some("test".into())))
+                .with_writer(move || CapturedWriter(writer.clone())),
+        );
+        tracing::subscriber::with_default(subscriber, log);
+        String::from_utf8(output.lock().unwrap().clone()).unwrap()
+    }
+
+    struct CapturedWriter(Arc<Mutex<Vec<u8>>>);
+
+    impl std::io::Write for CapturedWriter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn private_keys_are_redacted() {
+        let key_body = GITHUB_APP_PRIVATE_KEY.lines().nth(1).unwrap();
+        let output = capture(|| {
+            // Displayed, the newlines are escaped once by the JSON formatter. In the debug
+            // representation, they are escaped once more.
+            tracing::error!(key = %GITHUB_APP_PRIVATE_KEY, "Displayed key");
+            tracing::error!(key = ?GITHUB_APP_PRIVATE_KEY, "Debug key");
+        });
+
+        let lines = output.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 2, "{output}");
+        for line in lines {
+            assert!(line.contains("[REDACTED PRIVATE KEY]"), "{line}");
+            assert!(!line.contains(key_body), "{line}");
+            assert!(line.contains(r#""instance_id":"test""#), "{line}");
+        }
+    }
+}
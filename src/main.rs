@@ -2,33 +2,69 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
-use std::net::{SocketAddr, SocketAddrV4};
+use opentelemetry::trace::TracerProvider;
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    time::Duration,
+};
 use tap::TapFallible;
+use tracing::Level;
 use tracing_appender::non_blocking::NonBlocking;
-use tracing_subscriber::EnvFilter;
+use tracing_subscriber::{
+    EnvFilter, Layer, filter::Targets, fmt::format::JsonFields, layer::SubscriberExt,
+    util::SubscriberInitExt,
+};
 
 use crate::{
+    audit::{AUDIT_TARGET, AuditFormat},
+    cli::Command,
     context::Context,
-    server::{ServerConfig, server},
+    logging::{InstanceIdFormat, otel_tracer_provider},
+    server::{ServerConfig, reload_on_hangup, server, shutdown_signal},
     settings::Settings,
+    util::error_chain,
 };
 
+mod audit;
+mod cache;
+mod cli;
 mod context;
 mod endpoints;
+mod logging;
+mod nonce;
 mod oauth;
 mod oidc;
 mod policy;
 mod server;
 mod settings;
+#[cfg(test)]
+mod test_util;
 mod token;
 mod util;
 
 #[tokio::main]
 async fn main() -> Result<(), anyhow::Error> {
-    let mut args = std::env::args();
-    let _ = args.next();
-    let config_path = args.next();
-    let settings = Settings::new(config_path.map(|path| vec![path]))?;
+    let config_path = match cli::parse_args(std::env::args().skip(1)) {
+        Ok(Command::Serve { config_path }) => config_path,
+        Ok(Command::ValidateConfig { config_path }) => {
+            std::process::exit(cli::validate_config(config_path))
+        }
+        Ok(Command::TestPolicy(args)) => {
+            std::process::exit(cli::test_policy(args.into_iter()).await)
+        }
+        Err(message) => {
+            eprintln!("error: {message}");
+            std::process::exit(2);
+        }
+    };
+    let settings = Settings::new(config_path.clone().map(|path| vec![path]))?;
+    // Logging is not set up yet, and might depend on the invalid settings.
+    if let Err(errors) = settings.validate() {
+        for error in &errors {
+            eprintln!("error: {error}");
+        }
+        anyhow::bail!("the configuration has {} error(s)", errors.len());
+    }
 
     let (writer, _guard) = if let Some(log_directory) = &settings.log_directory {
         let file_appender = tracing_appender::rolling::daily(log_directory, "oidcx.log");
@@ -37,34 +73,133 @@ async fn main() -> Result<(), anyhow::Error> {
         NonBlocking::new(std::io::stdout())
     };
 
-    let _subscriber = tracing_subscriber::fmt()
-        .with_file(false)
-        .with_line_number(false)
-        .with_env_filter(EnvFilter::from_default_env())
-        .with_writer(writer)
-        .json()
+    let tracer_provider = settings
+        .otel_endpoint
+        .as_deref()
+        .map(otel_tracer_provider)
+        .transpose()?;
+
+    let (audit_writer, _audit_guard) = settings
+        .audit_log_path
+        .as_ref()
+        .map(|path| {
+            tracing_appender::non_blocking(tracing_appender::rolling::daily(path, "audit.log"))
+        })
+        .unzip();
+
+    // RUST_LOG is applied to each layer instead of globally, so it can't suppress the audit log.
+    tracing_subscriber::registry()
+        .with(
+            tracing_subscriber::fmt::layer()
+                .fmt_fields(JsonFields::new())
+                .event_format(InstanceIdFormat::new(settings.instance_id.clone()))
+                .with_writer(writer)
+                .with_filter(EnvFilter::from_default_env()),
+        )
+        .with(tracer_provider.as_ref().map(|provider| {
+            tracing_opentelemetry::layer()
+                .with_tracer(provider.tracer("oidcx"))
+                .with_filter(EnvFilter::from_default_env())
+        }))
+        .with(audit_writer.map(|writer| {
+            tracing_subscriber::fmt::layer()
+                .event_format(AuditFormat)
+                .with_writer(writer)
+                .with_filter(Targets::new().with_target(AUDIT_TARGET, Level::INFO))
+        }))
         .init();
 
-    let address = SocketAddr::V4(SocketAddrV4::new(
-        "0.0.0.0".parse()?,
+    let address = SocketAddr::new(
+        settings
+            .bind_address
+            .unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED)),
         settings.port.unwrap_or(8080),
-    ));
-    let context = Context::new(settings).await?;
+    );
+    if settings.enable_policy_test_endpoint {
+        tracing::warn!(
+            "The policy test endpoint is enabled, allowing anyone to probe the policy. \
+             Do not expose this instance publicly."
+        );
+    }
 
-    tracing::info!("Constructed context");
+    let shutdown_timeout = Duration::from_secs(settings.shutdown_timeout_seconds);
+    let tls_config = settings.tls.clone();
+    let request_body_max_bytes = settings.max_request_body_bytes as usize;
+    let context = match Context::new(settings).await {
+        Ok(context) => context,
+        Err(err) => {
+            // Also print the error outside of the logs, which might be in a file, to make the
+            // most common misconfigurations (like a wrong provider URL) obvious.
+            eprintln!("error: {}", error_chain(&err));
+            tracing::error!(error = ?err, "Failed to construct the context");
+            anyhow::bail!("failed to start oidcx");
+        }
+    };
 
+    tracing::info!(policy = ?context.state().policy, "Constructed context");
+
+    let reload_context = context.clone();
+    tokio::spawn(async move {
+        if let Err(err) = reload_on_hangup(reload_context, config_path).await {
+            tracing::error!(error = ?err, "Failed to listen for SIGHUP, reloading is disabled");
+        }
+    });
+
+    let in_flight = context.in_flight().clone();
     let http = server(ServerConfig {
         context,
         server_address: address,
+        tls_config,
+        request_body_max_bytes,
     })
     .or_else(|err| anyhow::bail!(err))?;
 
-    http.start()
-        .await
-        .tap_err(|err| {
-            tracing::error!(error = ?err, "HTTP server failed");
-        })
-        .or_else(|err| anyhow::bail!(err))?;
+    let http = http.start();
+    let signalled = tokio::select! {
+        result = http.wait_for_shutdown() => {
+            result
+                .tap_err(|err| {
+                    tracing::error!(error = ?err, "HTTP server failed");
+                })
+                .or_else(|err| anyhow::bail!(err))?;
+            false
+        }
+        result = shutdown_signal() => {
+            result?;
+            true
+        }
+    };
+
+    if signalled {
+        tracing::info!(
+            timeout_seconds = shutdown_timeout.as_secs(),
+            "Shutting down, waiting for in-flight requests to complete"
+        );
+        match tokio::time::timeout(shutdown_timeout, http.close()).await {
+            Ok(result) => result.or_else(|err| anyhow::bail!(err))?,
+            // Returning from main drops the runtime, aborting the requests still running.
+            Err(_) => {
+                let dropped = in_flight.list();
+                tracing::warn!(
+                    count = dropped.len(),
+                    "In-flight requests did not complete within the shutdown timeout, dropping them"
+                );
+                for request in dropped {
+                    tracing::warn!(
+                        request_id = %request.request_id,
+                        route = request.route,
+                        elapsed_seconds = request.started_at.elapsed().as_secs(),
+                        "Dropping the in-flight request"
+                    );
+                }
+            }
+        }
+    }
+
+    // Flush the spans that were not exported yet.
+    if let Some(tracer_provider) = tracer_provider {
+        tracer_provider.shutdown()?;
+    }
 
     Ok(())
 }
@@ -0,0 +1,94 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use chrono::{DateTime, Utc};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::oidc::{Claims, OidcError};
+
+/// How often to evict the IDs of tokens that expired from the cache.
+static EVICTION_INTERVAL: Duration = Duration::from_secs(60);
+
+/// IDs (`jti` claims) of the tokens already exchanged, kept until the tokens expire. IDs are only
+/// unique for a given issuer, so they are keyed by both.
+#[derive(Clone, Debug, Default)]
+pub struct NonceCache {
+    seen: Arc<Mutex<HashMap<(String, String), DateTime<Utc>>>>,
+}
+
+impl NonceCache {
+    /// Record the token as used, failing if it was already used before.
+    pub fn check_and_insert(&self, claims: &Claims, leeway: u64) -> Result<(), OidcError> {
+        let jti = claims.jti().ok_or(OidcError::MissingJti)?;
+        // Validated tokens always have an issuer, matching the provider that validated them.
+        let iss = claims.iss().unwrap_or_default();
+        // Tokens are accepted until their expiration plus the leeway, so remember them as long.
+        let expires_at = claims
+            .exp()
+            .and_then(|exp| DateTime::from_timestamp(exp + leeway as i64, 0))
+            .ok_or(OidcError::MissingExp)?;
+
+        let key = (iss.to_string(), jti.to_string());
+        let mut seen = self.seen.lock().unwrap();
+        if seen.get(&key).is_some_and(|expiry| *expiry >= Utc::now()) {
+            return Err(OidcError::TokenReplayed);
+        }
+        seen.insert(key, expires_at);
+        Ok(())
+    }
+
+    /// Periodically evict the expired entries, to keep the cache from growing forever.
+    pub fn spawn_eviction(&self) {
+        let seen = self.seen.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(EVICTION_INTERVAL);
+            loop {
+                interval.tick().await;
+                let now = Utc::now();
+                seen.lock().unwrap().retain(|_, expiry| *expiry >= now);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn claims(iss: &str, jti: &str) -> Claims {
+        serde_json::from_value(serde_json::json!({
+            "iss": iss,
+            "jti": jti,
+            "exp": Utc::now().timestamp() + 300,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn tokens_can_only_be_used_once() {
+        let nonces = NonceCache::default();
+        let claims = claims("https://token.actions.githubusercontent.com", "1");
+        nonces.check_and_insert(&claims, 0).unwrap();
+        assert!(matches!(
+            nonces.check_and_insert(&claims, 0),
+            Err(OidcError::TokenReplayed)
+        ));
+    }
+
+    #[test]
+    fn token_ids_are_scoped_to_their_issuer() {
+        let nonces = NonceCache::default();
+        nonces
+            .check_and_insert(
+                &claims("https://token.actions.githubusercontent.com", "1"),
+                0,
+            )
+            .unwrap();
+        nonces
+            .check_and_insert(&claims("https://oidc.circleci.com/org/example", "1"), 0)
+            .unwrap();
+    }
+}
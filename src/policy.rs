@@ -4,114 +4,406 @@
 
 use crate::endpoints::TokenRequest;
 use crate::oidc::Claims;
-use crate::token::github::{GitHubTokenError, GitHubTokens};
-use chrono::{DateTime, Duration, Utc};
-use oso::{Class, Oso, OsoError, PolarClass, ToPolar};
+use crate::token::aws::AwsTokenRequest;
+use crate::token::gcp::GcpTokenRequest;
+use crate::token::github::{
+    GitHubTokenError, GitHubTokenRequest, GitHubTokens, RepositoryMetadata,
+};
+use crate::token::oxide::{OxideError, OxideTokens};
+use chrono::{DateTime, Datelike, Duration, Timelike, Utc};
+use oso::{Class, Oso, OsoError, PolarClass, PolarValue, ToPolar};
+use regex::Regex;
+#[cfg(test)]
+use std::cell::Cell;
 use std::collections::HashMap;
 use std::fmt::Display;
-use std::path::Path;
-use std::sync::{Arc, Mutex};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, LazyLock, Mutex, RwLock};
+use std::time::SystemTime;
+
+// Kubernetes takes up to a minute to update mounted ConfigMaps anyway, so checking more often
+// wouldn't make changes apply noticeably faster.
+static POLICY_POLL_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5);
+
+// Policies only use a handful of fixed patterns, so the cache is only ever filled by patterns
+// built from claims, in which case it is simply emptied.
+static REGEX_CACHE_CAPACITY: usize = 256;
+static REGEX_CACHE: LazyLock<Mutex<HashMap<String, Regex>>> = LazyLock::new(Default::default);
 
 pub struct Policy {
-    oso: Oso,
+    /// Replaced as a whole when the policy is reloaded, so that queries never see a partially
+    /// loaded policy.
+    loaded: Arc<RwLock<LoadedPolicy>>,
     github_tokens: GitHubTokens,
-    github_visibility_cache: Arc<Mutex<HashMap<String, CachedVisibility>>>,
+    oxide_tokens: OxideTokens,
+    github_metadata_cache: Arc<Mutex<HashMap<String, CachedMetadata>>>,
+    github_metadata_cache_ttl: Duration,
+    loaded_files: Vec<PathBuf>,
+    registered_classes: Vec<String>,
 }
 
 impl Policy {
-    pub fn new(path: &Path, github_tokens: GitHubTokens) -> Result<Self, OsoError> {
-        let mut oso = Oso::new();
-        oso.register_class(GitHubClass::get_polar_class())?;
-        oso.register_class(OxideClass::get_polar_class())?;
-        oso.register_class(create_utils_class())?;
-        oso.load_files(vec![path])?;
+    pub fn new(
+        path: &Path,
+        github_tokens: GitHubTokens,
+        oxide_tokens: OxideTokens,
+        github_metadata_cache_ttl: Duration,
+    ) -> Result<Self, OsoError> {
+        let files = vec![path.to_path_buf()];
+        let (loaded, registered_classes) = LoadedPolicy::read(&files)?;
         Ok(Self {
-            oso,
+            loaded: Arc::new(RwLock::new(loaded)),
             github_tokens,
-            github_visibility_cache: Arc::new(Mutex::new(HashMap::new())),
+            oxide_tokens,
+            github_metadata_cache: Arc::new(Mutex::new(HashMap::new())),
+            github_metadata_cache_ttl,
+            loaded_files: files,
+            registered_classes,
         })
     }
 
+    /// Check whether the policy source loads successfully, without replacing the policy in use.
+    pub fn validate_source(content: &str) -> Result<(), OsoError> {
+        let (oso, _) = new_oso()?;
+        oso.load_str(content)
+    }
+
+    /// Load the policy files again, replacing the policy in use. If the new policy fails to load,
+    /// the previous one is kept.
+    pub fn reload_from_file(&self) -> Result<(), OsoError> {
+        reload_files(&self.loaded, &self.loaded_files)
+    }
+
+    /// Periodically check whether the policy files changed, and load them again when they do. If
+    /// the new policy fails to load, the previous one is kept.
+    pub fn spawn_reload_on_change(&self) {
+        // Only hold a weak reference, so the task stops once the policy is replaced by a reload.
+        // That's also why it reloads through `reload_files` rather than `reload_from_file`.
+        let loaded = Arc::downgrade(&self.loaded);
+        let files = self.loaded_files.clone();
+        tokio::spawn(async move {
+            let mut last_modified = modified_times(&files);
+            let mut interval = tokio::time::interval(POLICY_POLL_INTERVAL);
+            loop {
+                interval.tick().await;
+                let Some(loaded) = loaded.upgrade() else {
+                    break;
+                };
+                let modified = modified_times(&files);
+                if modified == last_modified {
+                    continue;
+                }
+                last_modified = modified;
+
+                tracing::info!(?files, "The policy changed, reloading it");
+                if let Err(err) = reload_files(&loaded, &files) {
+                    tracing::error!(
+                        error = ?err,
+                        "Failed to reload the policy, keeping the previous one"
+                    );
+                }
+            }
+        });
+    }
+
     pub async fn ensure_allowed(
         &self,
         claims: &Claims,
         request: &TokenRequest,
     ) -> Result<(), PolicyError> {
+        // A single snapshot is used, so a reload can't change the policy midway.
+        let loaded = self.loaded();
+        for permutation in self.permutations(&loaded, request).await? {
+            ensure_permutation(&loaded.oso, claims, permutation)?;
+        }
+        Ok(())
+    }
+
+    /// Describe how each permutation of the request fares against the policy, to explain why a
+    /// request was denied. Unlike `ensure_allowed`, evaluation continues after the first denied
+    /// permutation. Oso doesn't expose which rules it tries, so for a rule-level trace run oidcx
+    /// with the `POLAR_LOG` environment variable set instead.
+    pub async fn explain(&self, claims: &Claims, request: &TokenRequest) -> Vec<String> {
+        let loaded = self.loaded();
+        let permutations = match self.permutations(&loaded, request).await {
+            Ok(permutations) => permutations,
+            Err(err) => return vec![format!("failed to prepare the request: {err}")],
+        };
+
+        let mut trace = Vec::new();
+        for permutation in permutations {
+            let string_repr = permutation.to_string();
+            trace.push(match ensure_permutation(&loaded.oso, claims, permutation) {
+                Ok(()) => format!("allowed: {string_repr}"),
+                Err(PolicyError::NotMatching(_)) => {
+                    format!("denied: no allow_request rule matched {string_repr}")
+                }
+                Err(err) => format!("error: evaluating {string_repr} failed: {err}"),
+            });
+        }
+        trace
+    }
+
+    /// The policy in use. Oso instances are cheap to clone, and this avoids holding the lock during
+    /// queries.
+    fn loaded(&self) -> LoadedPolicy {
+        self.loaded.read().unwrap().clone()
+    }
+
+    /// Split the request into the individual permutations checked against the `loaded` policy.
+    async fn permutations(
+        &self,
+        loaded: &LoadedPolicy,
+        request: &TokenRequest,
+    ) -> Result<Vec<Permutation>, PolicyError> {
         match request {
-            TokenRequest::Oxide(oxide) => self.ensure_permutation(
-                claims,
-                OxideClass {
-                    silo: oxide.silo.clone(),
-                    duration: oxide.duration as _,
+            TokenRequest::Oxide(oxide) => Ok(vec![Permutation::Oxide(OxideClass {
+                silo: oxide.silo.clone(),
+                duration: oxide.duration_seconds() as _,
+                // Policies not referring to the project count can't observe its value. The ones
+                // that do can't be evaluated without it, so failing to fetch it denies the request.
+                project_count: if loaded.uses_project_count {
+                    self.oxide_tokens
+                        .project_count(&oxide.silo)
+                        .await
+                        .map_err(|e| PolicyError::GetProjectCount(oxide.silo.clone(), e))?
+                } else {
+                    0
                 },
-            ),
-            TokenRequest::GitHub(github) => {
-                for repository in &github.repositories {
-                    let repository_visibility = self.github_visibility(repository).await?;
-
-                    for permission in &github.permissions {
-                        self.ensure_permutation(
-                            claims,
-                            GitHubClass {
-                                repository: repository.clone(),
-                                repository_visibility: repository_visibility.clone(),
-                                permission: permission.clone(),
-                            },
-                        )?;
-                    }
-                }
-                Ok(())
+            })]),
+            TokenRequest::GitHub(github) => self.github_permutations(github).await,
+            // Packages tokens are regular installation tokens, so they are authorized as such.
+            TokenRequest::GitHubPackages(packages) => {
+                self.github_permutations(&packages.to_token_request()).await
             }
+            TokenRequest::Aws(AwsTokenRequest {
+                role_alias,
+                session_duration_seconds,
+            }) => Ok(vec![Permutation::Aws(AwsClass {
+                role_alias: role_alias.clone(),
+                session_duration: session_duration_seconds.map(|duration| duration as _),
+            })]),
+            TokenRequest::Gcp(GcpTokenRequest {
+                service_account_email,
+                scopes,
+            }) => Ok(scopes
+                .iter()
+                .map(|scope| {
+                    Permutation::Gcp(GcpClass {
+                        service_account_email: service_account_email.clone(),
+                        scope: scope.clone(),
+                    })
+                })
+                .collect()),
         }
     }
 
-    fn ensure_permutation<T: ToPolar + Display>(
+    async fn github_permutations(
         &self,
-        claims: &Claims,
-        permutation: T,
-    ) -> Result<(), PolicyError> {
-        let string_repr = permutation.to_string();
-        let mut result = self
-            .oso
-            .query_rule("allow_request", (claims.clone(), permutation))?;
-        match result.next() {
-            Some(Ok(_)) => Ok(()),
-            Some(Err(e)) => Err(e.into()),
-            None => Err(PolicyError::NotMatching(string_repr)),
+        github: &GitHubTokenRequest,
+    ) -> Result<Vec<Permutation>, PolicyError> {
+        github
+            .check_selection()
+            .map_err(PolicyError::InvalidGitHubRequest)?;
+
+        let mut permutations = Vec::new();
+        // There is no single repository to fetch the metadata of, so the policy has to decide
+        // based on the owner alone. The owner is only set when requesting all repositories.
+        if let Some(owner) = &github.owner {
+            for permission in &github.permissions {
+                permutations.push(Permutation::GitHub(GitHubClass {
+                    repository: format!("{owner}/*"),
+                    repository_selection: "all".into(),
+                    repository_visibility: String::new(),
+                    archived: false,
+                    default_branch: String::new(),
+                    topics: Vec::new(),
+                    permission: permission.clone(),
+                }));
+            }
+            return Ok(permutations);
+        }
+
+        for repository in &github.repositories {
+            let metadata = self.github_metadata(repository).await?;
+
+            for permission in &github.permissions {
+                permutations.push(Permutation::GitHub(GitHubClass {
+                    repository: repository.clone(),
+                    repository_selection: "selected".into(),
+                    repository_visibility: metadata.visibility.clone(),
+                    archived: metadata.archived,
+                    default_branch: metadata.default_branch.clone(),
+                    topics: metadata.topics.clone(),
+                    permission: permission.clone(),
+                }));
+            }
         }
+        Ok(permutations)
     }
 
-    async fn github_visibility(&self, repo: &str) -> Result<String, PolicyError> {
+    /// Forget the cached metadata of the repository, so it's fetched again when next needed.
+    #[cfg(test)]
+    pub fn invalidate_visibility_cache(&self, repo: &str) {
+        self.github_metadata_cache.lock().unwrap().remove(repo);
+    }
+
+    async fn github_metadata(&self, repo: &str) -> Result<RepositoryMetadata, PolicyError> {
         // We are not holding the lock across the await point below.
         {
-            let cache = self.github_visibility_cache.lock().unwrap();
+            let cache = self.github_metadata_cache.lock().unwrap();
             if let Some(cached) = cache.get(repo)
                 && cached.expires_at >= Utc::now()
             {
-                return Ok(cached.visibility.clone());
+                return Ok(cached.metadata.clone());
             }
         }
 
-        let visibility = self
+        let metadata = self
             .github_tokens
-            .repository_visibility(repo)
+            .repository_metadata(repo)
             .await
-            .map_err(|e| PolicyError::GetVisibility(repo.into(), e))?;
+            .map_err(|e| PolicyError::GetMetadata(repo.into(), e))?;
 
-        self.github_visibility_cache.lock().unwrap().insert(
+        self.github_metadata_cache.lock().unwrap().insert(
             repo.into(),
-            CachedVisibility {
-                visibility: visibility.clone(),
-                expires_at: Utc::now() + Duration::hours(1),
+            CachedMetadata {
+                metadata: metadata.clone(),
+                expires_at: Utc::now() + self.github_metadata_cache_ttl,
             },
         );
-        Ok(visibility)
+        Ok(metadata)
+    }
+}
+
+fn ensure_permutation<T: ToPolar + Display>(
+    oso: &Oso,
+    claims: &Claims,
+    permutation: T,
+) -> Result<(), PolicyError> {
+    let string_repr = permutation.to_string();
+    let mut result = oso.query_rule("allow_request", (claims.clone(), permutation))?;
+    match result.next() {
+        Some(Ok(_)) => Ok(()),
+        Some(Err(e)) => Err(e.into()),
+        None => Err(PolicyError::NotMatching(string_repr)),
+    }
+}
+
+/// A policy loaded from its files, along with what was learned from their sources.
+#[derive(Clone)]
+struct LoadedPolicy {
+    oso: Oso,
+    /// Whether the policy refers to `project_count`, which is expensive to fetch and requires
+    /// credentials for the silo, so it's only fetched for the policies using it. Mentions in
+    /// comments are harmless, they only cause the project count to be fetched needlessly.
+    uses_project_count: bool,
+}
+
+impl LoadedPolicy {
+    /// Read the policy files and load them. Each file is only read once, so that the policy and
+    /// `uses_project_count` can't disagree when a file changes in the meantime.
+    fn read(files: &[PathBuf]) -> Result<(Self, Vec<String>), OsoError> {
+        let sources = files
+            .iter()
+            .map(std::fs::read_to_string)
+            .collect::<Result<Vec<_>, _>>()?;
+        let (oso, registered_classes) = new_oso()?;
+        oso.load_str(&sources.join("\n"))?;
+        let loaded = LoadedPolicy {
+            oso,
+            uses_project_count: sources
+                .iter()
+                .any(|source| source.contains("project_count")),
+        };
+        Ok((loaded, registered_classes))
+    }
+}
+
+/// Load the policy files again and replace the `loaded` policy with them, keeping it if they fail
+/// to load.
+fn reload_files(loaded: &RwLock<LoadedPolicy>, files: &[PathBuf]) -> Result<(), OsoError> {
+    let (new, _) = LoadedPolicy::read(files)?;
+    *loaded.write().unwrap() = new;
+    tracing::info!(?files, "Reloaded the policy");
+    Ok(())
+}
+
+fn modified_times(files: &[PathBuf]) -> Vec<Option<SystemTime>> {
+    files
+        .iter()
+        .map(|file| {
+            std::fs::metadata(file)
+                .and_then(|metadata| metadata.modified())
+                .ok()
+        })
+        .collect()
+}
+
+/// Create an Oso instance with all the classes policies can refer to registered.
+fn new_oso() -> Result<(Oso, Vec<String>), OsoError> {
+    let mut oso = Oso::new();
+    let mut registered_classes = Vec::new();
+    for class in [
+        GitHubClass::get_polar_class(),
+        OxideClass::get_polar_class(),
+        AwsClass::get_polar_class(),
+        GcpClass::get_polar_class(),
+        create_utils_class(),
+    ] {
+        registered_classes.push(class.name.clone());
+        oso.register_class(class)?;
     }
+    Ok((oso, registered_classes))
 }
 
 impl std::fmt::Debug for Policy {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.write_str("Policy")
+        f.debug_struct("Policy")
+            .field("loaded_files", &self.loaded_files)
+            .field("registered_classes", &self.registered_classes)
+            .finish_non_exhaustive()
+    }
+}
+
+impl std::fmt::Display for Policy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let files = self
+            .loaded_files
+            .iter()
+            .map(|path| path.display().to_string())
+            .collect::<Vec<_>>();
+        write!(f, "policy loaded from {}", files.join(", "))
+    }
+}
+
+#[derive(Clone)]
+enum Permutation {
+    Oxide(OxideClass),
+    GitHub(GitHubClass),
+    Aws(AwsClass),
+    Gcp(GcpClass),
+}
+
+impl ToPolar for Permutation {
+    fn to_polar(self) -> PolarValue {
+        match self {
+            Permutation::Oxide(oxide) => oxide.to_polar(),
+            Permutation::GitHub(github) => github.to_polar(),
+            Permutation::Aws(aws) => aws.to_polar(),
+            Permutation::Gcp(gcp) => gcp.to_polar(),
+        }
+    }
+}
+
+impl std::fmt::Display for Permutation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Permutation::Oxide(oxide) => oxide.fmt(f),
+            Permutation::GitHub(github) => github.fmt(f),
+            Permutation::Aws(aws) => aws.fmt(f),
+            Permutation::Gcp(gcp) => gcp.fmt(f),
+        }
     }
 }
 
@@ -122,6 +414,8 @@ struct OxideClass {
     silo: String,
     #[polar(attribute)]
     duration: i64,
+    #[polar(attribute)]
+    project_count: i64,
 }
 
 impl std::fmt::Display for OxideClass {
@@ -136,8 +430,16 @@ struct GitHubClass {
     #[polar(attribute)]
     repository: String,
     #[polar(attribute)]
+    repository_selection: String,
+    #[polar(attribute)]
     repository_visibility: String,
     #[polar(attribute)]
+    archived: bool,
+    #[polar(attribute)]
+    default_branch: String,
+    #[polar(attribute)]
+    topics: Vec<String>,
+    #[polar(attribute)]
     permission: String,
 }
 
@@ -151,11 +453,59 @@ impl std::fmt::Display for GitHubClass {
     }
 }
 
-struct CachedVisibility {
-    visibility: String,
+#[derive(PolarClass, Clone)]
+#[polar(class_name = "AWS")]
+struct AwsClass {
+    #[polar(attribute)]
+    role_alias: String,
+    #[polar(attribute)]
+    session_duration: Option<i64>,
+}
+
+impl std::fmt::Display for AwsClass {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "AWS role {}", self.role_alias)
+    }
+}
+
+#[derive(PolarClass, Clone)]
+#[polar(class_name = "GCP")]
+struct GcpClass {
+    #[polar(attribute)]
+    service_account_email: String,
+    #[polar(attribute)]
+    scope: String,
+}
+
+impl std::fmt::Display for GcpClass {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "scope {} on service account {}",
+            self.scope, self.service_account_email
+        )
+    }
+}
+
+struct CachedMetadata {
+    metadata: RepositoryMetadata,
     expires_at: DateTime<Utc>,
 }
 
+/// Compile the pattern, reusing the regex compiled by a previous evaluation if possible.
+fn cached_regex(pattern: &str) -> Result<Regex, regex::Error> {
+    let mut cache = REGEX_CACHE.lock().unwrap();
+    if let Some(regex) = cache.get(pattern) {
+        return Ok(regex.clone());
+    }
+    let regex = Regex::new(pattern)?;
+    if cache.len() >= REGEX_CACHE_CAPACITY {
+        cache.clear();
+    }
+    cache.insert(pattern.to_string(), regex.clone());
+    Ok(regex)
+}
+
 pub(super) fn create_utils_class() -> Class {
     #[derive(Clone, PolarClass)]
     #[polar(class_name = "utils")]
@@ -163,15 +513,589 @@ pub(super) fn create_utils_class() -> Class {
 
     Utils::get_polar_class_builder()
         .add_class_method("concat", |a: String, b: String| format!("{a}{b}"))
+        .add_class_method("matches", |value: String, pattern: String| {
+            cached_regex(&pattern).map(|regex| regex.is_match(&value))
+        })
+        .add_class_method("starts_with", |s: String, prefix: String| {
+            s.starts_with(&prefix)
+        })
+        .add_class_method("ends_with", |s: String, suffix: String| {
+            s.ends_with(&suffix)
+        })
+        .add_class_method("contains", |s: String, needle: String| s.contains(&needle))
+        .add_class_method("split", |s: String, sep: String| {
+            s.split(&sep).map(String::from).collect::<Vec<_>>()
+        })
+        .add_class_method("list_contains", |list: Vec<String>, item: String| {
+            list.contains(&item)
+        })
+        .add_class_method("now_utc_hour", || now().hour() as i64)
+        .add_class_method("now_utc_weekday", || {
+            now().weekday().num_days_from_monday() as i64
+        })
+        .add_class_method("now_unix_timestamp", || now().timestamp())
+        .add_class_method("in_time_range", |start_hour: i64, end_hour: i64| {
+            let hour = now().hour() as i64;
+            // Ranges ending before they start wrap around midnight, like 22 to 6.
+            if start_hour <= end_hour {
+                start_hour <= hour && hour < end_hour
+            } else {
+                start_hour <= hour || hour < end_hour
+            }
+        })
         .build()
 }
 
+#[cfg(test)]
+thread_local! {
+    /// Time returned to the policies evaluated on this thread instead of the current time.
+    static FROZEN_NOW: Cell<Option<DateTime<Utc>>> = const { Cell::new(None) };
+}
+
+/// The current time, as seen by the time helpers of the utils class.
+fn now() -> DateTime<Utc> {
+    #[cfg(test)]
+    if let Some(now) = FROZEN_NOW.with(Cell::get) {
+        return now;
+    }
+    Utc::now()
+}
+
 #[derive(Debug, thiserror::Error)]
 pub enum PolicyError {
     #[error("Failed to evaluate the authorization policy")]
     Oso(#[from] OsoError),
     #[error("{0} does not match the authorization policy")]
     NotMatching(String),
-    #[error("failed to retrieve the repository visibility for {0}")]
-    GetVisibility(String, #[source] GitHubTokenError),
+    #[error("{0}")]
+    InvalidGitHubRequest(#[source] GitHubTokenError),
+    #[error("failed to retrieve the repository metadata for {0}")]
+    GetMetadata(String, #[source] GitHubTokenError),
+    #[error("failed to retrieve the number of projects in {0}")]
+    GetProjectCount(String, #[source] OxideError),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{
+        MockResponse, MockServer, github_settings, github_tokens, mock_github_installation,
+        temp_file,
+    };
+    use crate::token::github::RepositorySelection;
+
+    /// Load a policy from `source`, without credentials for GitHub or any silo.
+    fn load_policy(source: &str) -> Policy {
+        load_policy_with(source, GitHubTokens::default())
+    }
+
+    fn load_policy_with(source: &str, github_tokens: GitHubTokens) -> Policy {
+        let path = temp_file("policy.polar", source);
+        let policy = Policy::new(
+            &path,
+            github_tokens,
+            OxideTokens::default(),
+            Duration::hours(1),
+        )
+        .unwrap();
+        std::fs::remove_file(&path).unwrap();
+        policy
+    }
+
+    fn claims() -> Claims {
+        serde_json::from_value(serde_json::json!({ "sub": "repo:oxidecomputer/oidcx" })).unwrap()
+    }
+
+    /// Whether the policy allows an Oxide token to be requested with `claims`.
+    async fn allows(policy: &Policy, claims: serde_json::Value) -> bool {
+        let claims: Claims = serde_json::from_value(claims).unwrap();
+        policy
+            .ensure_allowed(&claims, &oxide_request())
+            .await
+            .is_ok()
+    }
+
+    fn oxide_request() -> TokenRequest {
+        serde_json::from_value(serde_json::json!({
+            "service": "oxide",
+            "silo": "https://example.sys.oxide.computer",
+            "duration": 3600,
+        }))
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn project_count_is_not_fetched_if_unused() {
+        let policy = load_policy(
+            r#"allow_request(_claims, oxide: Oxide) if
+                oxide.silo = "https://example.sys.oxide.computer";"#,
+        );
+        policy
+            .ensure_allowed(&claims(), &oxide_request())
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn project_count_is_fetched_if_used() {
+        let policy = load_policy(
+            r#"allow_request(_claims, oxide: Oxide) if
+                oxide.project_count < 10;"#,
+        );
+        let err = policy
+            .ensure_allowed(&claims(), &oxide_request())
+            .await
+            .unwrap_err();
+        assert!(matches!(err, PolicyError::GetProjectCount(..)), "{err}");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn changed_policy_is_reloaded() {
+        let path = temp_file("policy.polar", "allow_request(_claims, _request) if false;");
+        let policy = Policy::new(
+            &path,
+            GitHubTokens::default(),
+            OxideTokens::default(),
+            Duration::hours(1),
+        )
+        .unwrap();
+        policy.spawn_reload_on_change();
+        // Let the task record the modification time of the initial policy.
+        tokio::time::sleep(POLICY_POLL_INTERVAL).await;
+        assert!(
+            policy
+                .ensure_allowed(&claims(), &oxide_request())
+                .await
+                .is_err()
+        );
+
+        std::fs::write(&path, "allow_request(_claims, _request);").unwrap();
+        // Filesystems with a coarse timestamp resolution could otherwise report the same time.
+        std::fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(SystemTime::now() + std::time::Duration::from_secs(60))
+            .unwrap();
+        tokio::time::sleep(POLICY_POLL_INTERVAL * 2).await;
+        policy
+            .ensure_allowed(&claims(), &oxide_request())
+            .await
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn invalid_policy_changes_keep_the_previous_policy() {
+        let path = temp_file("policy.polar", "allow_request(_claims, _request);");
+        let policy = Policy::new(
+            &path,
+            GitHubTokens::default(),
+            OxideTokens::default(),
+            Duration::hours(1),
+        )
+        .unwrap();
+        policy.spawn_reload_on_change();
+        tokio::time::sleep(POLICY_POLL_INTERVAL).await;
+
+        std::fs::write(&path, "allow_request(_claims, _request) if").unwrap();
+        std::fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(SystemTime::now() + std::time::Duration::from_secs(60))
+            .unwrap();
+        tokio::time::sleep(POLICY_POLL_INTERVAL * 2).await;
+        policy
+            .ensure_allowed(&claims(), &oxide_request())
+            .await
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn policy_is_reloaded_from_its_file() {
+        let path = temp_file("policy.polar", "allow_request(_claims, _request) if false;");
+        let policy = Policy::new(
+            &path,
+            GitHubTokens::default(),
+            OxideTokens::default(),
+            Duration::hours(1),
+        )
+        .unwrap();
+        assert!(!policy.loaded().uses_project_count);
+
+        std::fs::write(
+            &path,
+            "allow_request(_claims, oxide: Oxide) if oxide.project_count < 10;",
+        )
+        .unwrap();
+        policy.reload_from_file().unwrap();
+        assert!(policy.loaded().uses_project_count);
+
+        std::fs::write(&path, "allow_request(_claims, _request) if").unwrap();
+        assert!(policy.reload_from_file().is_err());
+        assert!(policy.loaded().uses_project_count);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn cached_regex_reuses_compiled_patterns() {
+        let regex = cached_regex("^refs/heads/release-").unwrap();
+        assert!(regex.is_match("refs/heads/release-1.0"));
+        assert!(!regex.is_match("refs/heads/main"));
+        assert!(
+            REGEX_CACHE
+                .lock()
+                .unwrap()
+                .contains_key("^refs/heads/release-")
+        );
+
+        let again = cached_regex("^refs/heads/release-").unwrap();
+        assert_eq!(regex.as_str(), again.as_str());
+    }
+
+    #[test]
+    fn cached_regex_rejects_invalid_patterns() {
+        assert!(cached_regex("refs/(heads").is_err());
+        assert!(!REGEX_CACHE.lock().unwrap().contains_key("refs/(heads"));
+    }
+
+    #[tokio::test]
+    async fn all_repositories_are_checked_as_the_owner() {
+        let policy = load_policy("");
+        let request = |owner: Option<&str>, repository_selection| {
+            TokenRequest::GitHub(GitHubTokenRequest {
+                repositories: Vec::new(),
+                permissions: vec!["contents:read".into()],
+                repository_selection: Some(repository_selection),
+                owner: owner.map(Into::into),
+            })
+        };
+
+        let permutations = policy
+            .permutations(
+                &policy.loaded(),
+                &request(Some("oxidecomputer"), RepositorySelection::All),
+            )
+            .await
+            .unwrap();
+        let [Permutation::GitHub(github)] = permutations.as_slice() else {
+            panic!("expected a single GitHub permutation");
+        };
+        assert_eq!(github.repository, "oxidecomputer/*");
+        assert_eq!(github.repository_selection, "all");
+        assert_eq!(github.permission, "contents:read");
+
+        for request in [
+            request(None, RepositorySelection::All),
+            request(Some("oxidecomputer"), RepositorySelection::Selected),
+        ] {
+            let Err(err) = policy.permutations(&policy.loaded(), &request).await else {
+                panic!("the request was accepted");
+            };
+            assert!(
+                matches!(err, PolicyError::InvalidGitHubRequest(..)),
+                "{err}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn invalidated_visibility_is_fetched_again() {
+        let server = MockServer::start().await;
+        mock_github_installation(&server, "oxidecomputer");
+        let repository = |visibility: &str| {
+            MockResponse::json(
+                200,
+                serde_json::json!({
+                    "visibility": visibility,
+                    "archived": false,
+                    "default_branch": "main",
+                }),
+            )
+        };
+        server.respond("/repos/oxidecomputer/oidcx", repository("private"));
+        let policy = load_policy_with(
+            "allow_request(_claims, _request);",
+            github_tokens(github_settings(&server)),
+        );
+
+        let visibility = async || {
+            let metadata = policy.github_metadata("oxidecomputer/oidcx").await;
+            metadata.unwrap().visibility
+        };
+        assert_eq!(visibility().await, "private");
+        server.respond("/repos/oxidecomputer/oidcx", repository("public"));
+        assert_eq!(visibility().await, "private");
+
+        policy.invalidate_visibility_cache("oxidecomputer/oidcx");
+        assert_eq!(visibility().await, "public");
+        assert_eq!(server.request_count("/repos/oxidecomputer/oidcx"), 2);
+    }
+
+    /// Whether `condition` holds, evaluated by an Oso instance only knowing the utils class.
+    fn utils_condition(condition: &str) -> bool {
+        let mut oso = Oso::new();
+        oso.register_class(create_utils_class()).unwrap();
+        oso.load_str(&format!("check(_) if {condition};")).unwrap();
+        matches!(oso.query_rule("check", (0,)).unwrap().next(), Some(Ok(_)))
+    }
+
+    /// Evaluate `condition` as if the current time was `now`.
+    fn utils_condition_at(now: &str, condition: &str) -> bool {
+        FROZEN_NOW.with(|frozen| frozen.set(Some(now.parse().unwrap())));
+        let result = utils_condition(condition);
+        FROZEN_NOW.with(|frozen| frozen.set(None));
+        result
+    }
+
+    #[test]
+    fn utils_now() {
+        // A Wednesday.
+        let now = "2026-10-14T13:45:00Z";
+        assert!(utils_condition_at(now, "utils.now_utc_hour() = 13"));
+        assert!(utils_condition_at(now, "utils.now_utc_weekday() = 2"));
+        assert!(utils_condition_at(
+            now,
+            "utils.now_unix_timestamp() = 1791985500"
+        ));
+        assert!(utils_condition_at(
+            "2026-10-18T00:00:00Z",
+            "utils.now_utc_weekday() = 6"
+        ));
+    }
+
+    #[test]
+    fn utils_in_time_range() {
+        assert!(utils_condition_at(
+            "2026-10-14T09:00:00Z",
+            "utils.in_time_range(9, 17)"
+        ));
+        assert!(utils_condition_at(
+            "2026-10-14T16:59:59Z",
+            "utils.in_time_range(9, 17)"
+        ));
+        assert!(!utils_condition_at(
+            "2026-10-14T17:00:00Z",
+            "utils.in_time_range(9, 17)"
+        ));
+        assert!(!utils_condition_at(
+            "2026-10-14T08:59:59Z",
+            "utils.in_time_range(9, 17)"
+        ));
+    }
+
+    #[test]
+    fn utils_in_time_range_wraps_around_midnight() {
+        assert!(utils_condition_at(
+            "2026-10-14T23:00:00Z",
+            "utils.in_time_range(22, 6)"
+        ));
+        assert!(utils_condition_at(
+            "2026-10-14T05:59:59Z",
+            "utils.in_time_range(22, 6)"
+        ));
+        assert!(!utils_condition_at(
+            "2026-10-14T06:00:00Z",
+            "utils.in_time_range(22, 6)"
+        ));
+        assert!(!utils_condition_at(
+            "2026-10-14T12:00:00Z",
+            "utils.in_time_range(22, 6)"
+        ));
+    }
+
+    #[test]
+    fn utils_concat() {
+        assert!(utils_condition(
+            r#"utils.concat("oxide", "computer") = "oxidecomputer""#
+        ));
+    }
+
+    #[test]
+    fn utils_starts_with() {
+        assert!(utils_condition(
+            r#"utils.starts_with("refs/heads/main", "refs/heads/")"#
+        ));
+        assert!(!utils_condition(
+            r#"utils.starts_with("refs/tags/v1", "refs/heads/")"#
+        ));
+    }
+
+    #[test]
+    fn utils_ends_with() {
+        assert!(utils_condition(
+            r#"utils.ends_with("oxidecomputer/oidcx", "/oidcx")"#
+        ));
+        assert!(!utils_condition(
+            r#"utils.ends_with("oxidecomputer/omicron", "/oidcx")"#
+        ));
+    }
+
+    #[test]
+    fn utils_contains() {
+        assert!(utils_condition(
+            r#"utils.contains("repo:oxidecomputer/oidcx", "oxide")"#
+        ));
+        assert!(!utils_condition(
+            r#"utils.contains("repo:example/oidcx", "oxide")"#
+        ));
+    }
+
+    #[test]
+    fn utils_split() {
+        assert!(utils_condition(
+            r#"utils.split("oxidecomputer/oidcx", "/") = ["oxidecomputer", "oidcx"]"#
+        ));
+    }
+
+    #[test]
+    fn utils_list_contains() {
+        assert!(utils_condition(
+            r#"utils.list_contains(["main", "release"], "main")"#
+        ));
+        assert!(!utils_condition(
+            r#"utils.list_contains(["main", "release"], "dev")"#
+        ));
+    }
+
+    #[tokio::test]
+    async fn claims_can_be_matched_exactly_by_list_prefix_and_pattern() {
+        let policy = load_policy(
+            r#"allow_request(claims, _request: Oxide) if
+                claims.repository_owner == "oxidecomputer" and
+                claims.environment in ["staging", "production"] and
+                utils.starts_with(claims.ref, "refs/heads/") and
+                utils.matches(claims.workflow_ref, "^oxidecomputer/[a-z-]+/[.]github/workflows/deploy[.]yml@");"#,
+        );
+        let allowed = |overrides: serde_json::Value| {
+            let mut claims = serde_json::json!({
+                "repository_owner": "oxidecomputer",
+                "environment": "production",
+                "ref": "refs/heads/main",
+                "workflow_ref": "oxidecomputer/oidcx/.github/workflows/deploy.yml@refs/heads/main",
+            });
+            for (name, value) in overrides.as_object().unwrap() {
+                claims[name] = value.clone();
+            }
+            let claims: Claims = serde_json::from_value(claims).unwrap();
+            let policy = &policy;
+            async move {
+                policy
+                    .ensure_allowed(&claims, &oxide_request())
+                    .await
+                    .is_ok()
+            }
+        };
+
+        assert!(allowed(serde_json::json!({})).await);
+        assert!(!allowed(serde_json::json!({ "repository_owner": "example" })).await);
+        assert!(!allowed(serde_json::json!({ "environment": "development" })).await);
+        assert!(!allowed(serde_json::json!({ "ref": "refs/pull/1/merge" })).await);
+        assert!(
+            !allowed(serde_json::json!({
+                "workflow_ref": "oxidecomputer/oidcx/.github/workflows/test.yml@refs/heads/main",
+            }))
+            .await
+        );
+    }
+
+    /// Load the policy example of the README containing `needle`.
+    fn readme_policy(needle: &str) -> Policy {
+        let example = include_str!("../README.md")
+            .split("```polar\n")
+            .skip(1)
+            .filter_map(|rest| rest.split_once("```").map(|(example, _)| example))
+            .find(|example| example.contains(needle))
+            .unwrap_or_else(|| panic!("no policy example of the README contains {needle}"));
+        load_policy(example)
+    }
+
+    #[tokio::test]
+    async fn readme_policy_examples() {
+        // A line identifying each example, claims it allows, and claims it denies.
+        let examples = [
+            (
+                r#"claims.runType == "TRACKED""#,
+                serde_json::json!({
+                    "iss": "https://example.app.spacelift.io",
+                    "callerType": "stack",
+                    "callerId": "infrastructure",
+                    "runType": "TRACKED",
+                }),
+                vec![serde_json::json!({
+                    "iss": "https://example.app.spacelift.io",
+                    "callerType": "stack",
+                    "callerId": "infrastructure",
+                    "runType": "PROPOSED",
+                })],
+            ),
+            (
+                r#"claims.job_workflow_sha == "#,
+                serde_json::json!({
+                    "iss": "https://token.actions.githubusercontent.com",
+                    "job_workflow_ref": "oxidecomputer/workflows/.github/workflows/deploy.yml@refs/heads/main",
+                    "job_workflow_sha": "0123456789abcdef0123456789abcdef01234567",
+                }),
+                vec![serde_json::json!({
+                    "iss": "https://token.actions.githubusercontent.com",
+                    "job_workflow_ref": "oxidecomputer/workflows/.github/workflows/deploy.yml@refs/heads/main",
+                    "job_workflow_sha": "89abcdef0123456789abcdef0123456789abcdef",
+                })],
+            ),
+            (
+                "system:serviceaccount:production:deploy-workflow",
+                serde_json::json!({
+                    "iss": "https://kubernetes.example.com",
+                    "sub": "system:serviceaccount:production:deploy-workflow",
+                }),
+                vec![serde_json::json!({
+                    "iss": "https://kubernetes.example.com",
+                    "sub": "system:serviceaccount:staging:deploy-workflow",
+                })],
+            ),
+            (
+                r#"claims.runner_environment == "github-hosted""#,
+                serde_json::json!({
+                    "iss": "https://token.actions.githubusercontent.com",
+                    "repository": "oxidecomputer/oidcx",
+                    "runner_environment": "github-hosted",
+                }),
+                vec![
+                    serde_json::json!({
+                        "iss": "https://token.actions.githubusercontent.com",
+                        "repository": "oxidecomputer/oidcx",
+                        "runner_environment": "self-hosted",
+                    }),
+                    serde_json::json!({
+                        "iss": "https://token.actions.githubusercontent.com",
+                        "repository": "oxidecomputer/oidcx",
+                    }),
+                ],
+            ),
+            (
+                r#"claims.enterprise == "my-enterprise""#,
+                serde_json::json!({
+                    "iss": "https://token.actions.githubusercontent.com",
+                    "enterprise": "my-enterprise",
+                }),
+                vec![
+                    serde_json::json!({
+                        "iss": "https://token.actions.githubusercontent.com",
+                        "enterprise": "other-ent",
+                    }),
+                    serde_json::json!({ "iss": "https://token.actions.githubusercontent.com" }),
+                ],
+            ),
+        ];
+
+        for (needle, allowed, denied) in examples {
+            let policy = readme_policy(needle);
+            assert!(allows(&policy, allowed).await, "{needle}");
+            for claims in denied {
+                assert!(!allows(&policy, claims.clone()).await, "{needle}: {claims}");
+            }
+        }
+    }
 }
@@ -2,16 +2,31 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
-use dropshot::{ApiDescription, ConfigDropshot, EndpointTagPolicy, HttpServerStarter, TagConfig};
+use dropshot::{
+    ApiDescription, ConfigDropshot, ConfigTls, EndpointTagPolicy, HttpServerStarter, TagConfig,
+};
 use slog::Drain;
 use std::{error::Error, net::SocketAddr};
+use tokio::signal::unix::{SignalKind, signal};
 use tracing_slog::TracingSlogDrain;
 
-use crate::{context::Context, endpoints::exchange};
+use crate::{
+    context::Context,
+    endpoints::{
+        admin::{
+            github_apps, github_installations, github_rate_limits, providers, providers_refresh,
+        },
+        capabilities, exchange, exchange_batch, openapi,
+        policy::{policy_test, policy_validate},
+    },
+    settings::TlsConfig,
+};
 
 pub struct ServerConfig {
     pub context: Context,
     pub server_address: SocketAddr,
+    pub tls_config: Option<TlsConfig>,
+    pub request_body_max_bytes: usize,
 }
 
 pub fn server(
@@ -19,7 +34,7 @@ pub fn server(
 ) -> Result<HttpServerStarter<Context>, Box<dyn Error + Send + Sync>> {
     let config_dropshot = ConfigDropshot {
         bind_address: config.server_address,
-        default_request_body_max_bytes: 500 * 1024 * 1024,
+        default_request_body_max_bytes: config.request_body_max_bytes,
         ..Default::default()
     };
 
@@ -30,6 +45,29 @@ pub fn server(
         slog::Logger::root(async_drain, slog::o!())
     };
 
+    let api = api();
+
+    // The certificate and key are only read at startup: rotating them requires a restart.
+    let tls = match config.tls_config {
+        Some(tls) => Some(ConfigTls::AsBytes {
+            certs: std::fs::read(&tls.cert_path).map_err(|err| {
+                format!(
+                    "failed to read the TLS certificate at {}: {err}",
+                    tls.cert_path.display()
+                )
+            })?,
+            // Avoid including the path of the key in errors, as it ends up in the logs.
+            key: std::fs::read(&tls.key_path)
+                .map_err(|err| format!("failed to read the TLS private key: {err}"))?,
+        }),
+        None => None,
+    };
+
+    HttpServerStarter::new_with_tls(&config_dropshot, api, config.context, &dropshot_logger, tls)
+}
+
+/// Describe all the endpoints served by oidcx.
+pub fn api() -> ApiDescription<Context> {
     let mut api = ApiDescription::new().tag_config(TagConfig {
         allow_other_tags: false,
         policy: EndpointTagPolicy::Any,
@@ -37,6 +75,98 @@ pub fn server(
     });
 
     api.register(exchange).expect("Failed to register endpoint");
+    api.register(exchange_batch)
+        .expect("Failed to register endpoint");
+    api.register(capabilities)
+        .expect("Failed to register endpoint");
+    api.register(openapi).expect("Failed to register endpoint");
+    api.register(policy_test)
+        .expect("Failed to register endpoint");
+    api.register(policy_validate)
+        .expect("Failed to register endpoint");
+    api.register(providers)
+        .expect("Failed to register endpoint");
+    api.register(providers_refresh)
+        .expect("Failed to register endpoint");
+    api.register(github_apps)
+        .expect("Failed to register endpoint");
+    api.register(github_installations)
+        .expect("Failed to register endpoint");
+    api.register(github_rate_limits)
+        .expect("Failed to register endpoint");
+
+    api
+}
+
+/// Wait until the process is asked to stop, either with SIGINT (Ctrl+C) or SIGTERM (sent by
+/// Kubernetes and most service managers).
+pub async fn shutdown_signal() -> std::io::Result<()> {
+    let mut terminate = signal(SignalKind::terminate())?;
+    tokio::select! {
+        result = tokio::signal::ctrl_c() => result,
+        _ = terminate.recv() => Ok(()),
+    }
+}
+
+/// Reload the settings every time the process receives SIGHUP, until the signal handler can't be
+/// installed. A failed reload keeps the previous settings in use.
+pub async fn reload_on_hangup(
+    context: Context,
+    config_path: Option<String>,
+) -> std::io::Result<()> {
+    let mut hangup = signal(SignalKind::hangup())?;
+    while hangup.recv().await.is_some() {
+        tracing::info!("Received SIGHUP, reloading the settings");
+        match context
+            .reload(config_path.clone().map(|path| vec![path]))
+            .await
+        {
+            Ok(summary) => {
+                tracing::info!(
+                    added_issuers = ?summary.added_issuers,
+                    removed_issuers = ?summary.removed_issuers,
+                    "Reloaded the settings"
+                );
+                if !summary.restart_required.is_empty() {
+                    tracing::warn!(
+                        settings = ?summary.restart_required,
+                        "Some changed settings only take effect after a restart"
+                    );
+                }
+            }
+            Err(err) => tracing::error!(
+                error = ?err,
+                "Failed to reload the settings, keeping the previous ones"
+            ),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_util::{start_server, test_settings};
+
+    #[tokio::test]
+    async fn oversized_bodies_are_rejected() {
+        let settings = test_settings("")
+            .with_max_request_body_bytes(1024)
+            .build()
+            .unwrap();
+        let server = start_server(settings).await;
+        let url = format!("http://{}/exchange", server.local_addr());
+
+        let response = reqwest::Client::new()
+            .post(&url)
+            .header("content-type", "application/json")
+            .body(format!(r#"{{"padding": "{}"}}"#, "a".repeat(2048)))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 400);
+        let body = response.text().await.unwrap();
+        assert!(body.contains("exceeded maximum size"), "{body}");
 
-    HttpServerStarter::new(&config_dropshot, api, config.context, &dropshot_logger)
+        server.close().await.unwrap();
+    }
 }
@@ -3,28 +3,149 @@
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
 use std::collections::HashMap;
-use std::path::PathBuf;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
 
-use config::{Config, ConfigError, File};
-use serde::Deserialize;
+use config::{Config, ConfigError, Environment, File};
+use reqwest::{ClientBuilder, NoProxy, Proxy};
+use secrecy::SecretString;
+use serde::{Deserialize, Deserializer};
 
 use crate::oidc::OidcProvider;
 
 #[derive(Debug, Deserialize)]
 pub struct Settings {
-    pub audience: String,
+    /// Audiences accepted in the `aud` claim of JWTs. A single string is also accepted, as older
+    /// versions of oidcx only supported one audience.
+    #[serde(alias = "audience", deserialize_with = "deserialize_audiences")]
+    pub audiences: Vec<String>,
+    #[serde(default = "default_require_audience")]
+    pub require_audience: bool,
     pub policy_path: PathBuf,
+    /// Load the policy again whenever the file changes.
+    #[serde(default)]
+    pub policy_reload_on_change: bool,
     pub log_directory: Option<String>,
+    /// Identifier included in every log event. Defaults to the hostname.
+    #[serde(default)]
+    pub instance_id: Option<String>,
+    /// Directory to write the audit log of token exchanges to, rotated daily.
+    #[serde(default)]
+    pub audit_log_path: Option<PathBuf>,
+    /// gRPC endpoint of an OpenTelemetry collector to export traces to.
+    #[serde(default)]
+    pub otel_endpoint: Option<String>,
+    /// Address to bind the service to, either IPv4 or IPv6.
+    #[serde(default)]
+    pub bind_address: Option<IpAddr>,
     pub port: Option<u16>,
+    #[serde(default)]
+    pub tls: Option<TlsConfig>,
+    /// Larger request bodies are rejected before being parsed.
+    #[serde(default = "default_max_request_body_bytes")]
+    pub max_request_body_bytes: u64,
+    /// Maximum number of requests in a single `POST /exchange/batch` call.
+    #[serde(default = "default_max_batch_size")]
+    pub max_batch_size: usize,
+    /// How long to wait for in-flight requests to complete when shutting down.
+    #[serde(default = "default_shutdown_timeout_seconds")]
+    pub shutdown_timeout_seconds: u64,
+    #[serde(default)]
+    pub enable_simulate_mode: bool,
+    #[serde(default)]
+    pub enable_policy_test_endpoint: bool,
+    /// Explain which parts of a denied request didn't match the policy in the error response.
+    #[serde(default)]
+    pub verbose_policy_errors: bool,
+    /// Serve the OpenAPI description of the service at `GET /openapi.json`.
+    #[serde(default = "default_expose_openapi")]
+    pub expose_openapi: bool,
+    /// Bearer token required by the admin endpoints, which are disabled if missing.
+    #[serde(default)]
+    pub admin_token: Option<SecretString>,
+    /// Reject tokens whose `jti` was already exchanged. Requires all providers to set `jti`.
+    #[serde(default)]
+    pub enable_replay_prevention: bool,
+    /// Return the same GitHub token when an identity token makes the same request again.
+    #[serde(default)]
+    pub enable_response_cache: bool,
+    #[serde(default = "default_response_cache_max_entries")]
+    pub response_cache_max_entries: usize,
+    /// Tolerance in seconds when checking the time-based claims of JWTs.
+    #[serde(default = "default_jwt_clock_skew_seconds")]
+    pub jwt_clock_skew_seconds: u64,
     pub providers: Vec<OidcProvider>,
+    /// Timeout for fetching the configuration and keys of each provider, in seconds.
+    #[serde(default = "default_oidc_startup_timeout_seconds")]
+    pub oidc_startup_timeout_seconds: u64,
+    /// Whether to refuse to start when a provider can't be reached, rather than skipping it.
+    #[serde(default = "default_fail_on_provider_startup_error")]
+    pub fail_on_provider_startup_error: bool,
+    /// Proxy used for the requests made to the providers and to GitHub.
+    #[serde(default)]
+    pub proxy: ProxySettings,
     #[serde(default)]
     pub oxide: Option<SettingsOxide>,
     #[serde(default)]
-    pub github: Option<SettingsGitHubApp>,
+    pub github: Option<SettingsGitHub>,
+    #[serde(default)]
+    pub aws: Option<SettingsAws>,
+    #[serde(default)]
+    pub gcp: Option<SettingsGcp>,
 }
 
 impl Settings {
+    pub fn builder() -> SettingsBuilder {
+        SettingsBuilder {
+            policy_path: None,
+            settings: Settings {
+                audiences: Vec::new(),
+                require_audience: default_require_audience(),
+                // Replaced with the path set on the builder.
+                policy_path: PathBuf::new(),
+                policy_reload_on_change: false,
+                log_directory: None,
+                instance_id: None,
+                audit_log_path: None,
+                otel_endpoint: None,
+                bind_address: None,
+                port: None,
+                tls: None,
+                max_request_body_bytes: default_max_request_body_bytes(),
+                max_batch_size: default_max_batch_size(),
+                shutdown_timeout_seconds: default_shutdown_timeout_seconds(),
+                enable_simulate_mode: false,
+                enable_policy_test_endpoint: false,
+                verbose_policy_errors: false,
+                expose_openapi: default_expose_openapi(),
+                admin_token: None,
+                enable_replay_prevention: false,
+                enable_response_cache: false,
+                response_cache_max_entries: default_response_cache_max_entries(),
+                jwt_clock_skew_seconds: default_jwt_clock_skew_seconds(),
+                providers: Vec::new(),
+                oidc_startup_timeout_seconds: default_oidc_startup_timeout_seconds(),
+                fail_on_provider_startup_error: default_fail_on_provider_startup_error(),
+                proxy: ProxySettings::default(),
+                oxide: None,
+                github: None,
+                aws: None,
+                gcp: None,
+            },
+        }
+    }
+
     pub fn new(config_sources: Option<Vec<String>>) -> Result<Self, ConfigError> {
+        Self::from_sources(
+            config_sources,
+            Environment::with_prefix("OIDCX").separator("__"),
+        )
+    }
+
+    fn from_sources(
+        config_sources: Option<Vec<String>>,
+        environment: Environment,
+    ) -> Result<Self, ConfigError> {
         let mut config =
             Config::builder().add_source(File::with_name("settings.toml").required(false));
 
@@ -32,30 +153,776 @@ impl Settings {
             config = config.add_source(File::with_name(&source).required(false));
         }
 
+        // Added last, so environment variables override the files.
+        config = config.add_source(environment);
+
         config.build()?.try_deserialize()
     }
+
+    /// Check the settings for mistakes that deserialization can't catch, reporting all of them at
+    /// once rather than failing on the first one used.
+    pub fn validate(&self) -> Result<(), Vec<SettingsValidationError>> {
+        let mut errors = Vec::new();
+
+        if self.providers.is_empty() {
+            errors.push(SettingsValidationError::NoProviders);
+        }
+        if let Err(err) = std::fs::File::open(&self.policy_path) {
+            errors.push(SettingsValidationError::PolicyUnreadable(
+                self.policy_path.clone(),
+                err,
+            ));
+        }
+        if self.port == Some(0) {
+            errors.push(SettingsValidationError::InvalidPort);
+        }
+        if let Some(oxide) = &self.oxide {
+            for (silo, token_path) in &oxide.silos {
+                if let Err(err) = std::fs::File::open(token_path) {
+                    errors.push(SettingsValidationError::SiloTokenUnreadable(
+                        silo.clone(),
+                        token_path.clone(),
+                        err,
+                    ));
+                }
+            }
+            if let Some(directory) = &oxide.token_directory
+                && let Err(err) = std::fs::read_dir(directory)
+            {
+                errors.push(SettingsValidationError::TokenDirectoryUnreadable(
+                    directory.clone(),
+                    err,
+                ));
+            }
+        }
+        if let Some(github) = &self.github {
+            for app in github.apps() {
+                match (&app.private_key_path, &app.private_key_env) {
+                    (Some(_), Some(_)) => errors.push(
+                        SettingsValidationError::GitHubPrivateKeyConflict(app.client_id.clone()),
+                    ),
+                    (None, None) => errors.push(SettingsValidationError::GitHubPrivateKeyMissing(
+                        app.client_id.clone(),
+                    )),
+                    (Some(path), None) => {
+                        if let Err(err) = std::fs::File::open(path) {
+                            errors.push(SettingsValidationError::GitHubPrivateKeyUnreadable(
+                                path.clone(),
+                                err,
+                            ));
+                        }
+                    }
+                    (None, Some(var)) => {
+                        if std::env::var_os(var).is_none() {
+                            errors.push(SettingsValidationError::GitHubPrivateKeyEnvMissing(
+                                var.clone(),
+                            ));
+                        }
+                    }
+                }
+            }
+        }
+        if let Some(log_directory) = &self.log_directory
+            && !is_writable_directory(log_directory.as_ref())
+        {
+            errors.push(SettingsValidationError::LogDirectoryNotWritable(
+                log_directory.into(),
+            ));
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+/// Builds [`Settings`] programmatically, without reading any configuration file. Fields that aren't
+/// set keep the same defaults as when they're missing from the configuration file.
+#[derive(Debug)]
+pub struct SettingsBuilder {
+    policy_path: Option<PathBuf>,
+    settings: Settings,
+}
+
+impl SettingsBuilder {
+    pub fn with_audience(mut self, audience: impl Into<String>) -> Self {
+        self.settings.audiences.push(audience.into());
+        self
+    }
+
+    pub fn with_policy_path(mut self, policy_path: impl Into<PathBuf>) -> Self {
+        self.policy_path = Some(policy_path.into());
+        self
+    }
+
+    pub fn with_provider(mut self, provider: OidcProvider) -> Self {
+        self.settings.providers.push(provider);
+        self
+    }
+
+    pub fn with_policy_reload_on_change(mut self, policy_reload_on_change: bool) -> Self {
+        self.settings.policy_reload_on_change = policy_reload_on_change;
+        self
+    }
+
+    pub fn with_require_audience(mut self, require_audience: bool) -> Self {
+        self.settings.require_audience = require_audience;
+        self
+    }
+
+    pub fn with_log_directory(mut self, log_directory: String) -> Self {
+        self.settings.log_directory = Some(log_directory);
+        self
+    }
+
+    pub fn with_instance_id(mut self, instance_id: String) -> Self {
+        self.settings.instance_id = Some(instance_id);
+        self
+    }
+
+    pub fn with_audit_log_path(mut self, audit_log_path: PathBuf) -> Self {
+        self.settings.audit_log_path = Some(audit_log_path);
+        self
+    }
+
+    pub fn with_otel_endpoint(mut self, otel_endpoint: String) -> Self {
+        self.settings.otel_endpoint = Some(otel_endpoint);
+        self
+    }
+
+    pub fn with_bind_address(mut self, bind_address: IpAddr) -> Self {
+        self.settings.bind_address = Some(bind_address);
+        self
+    }
+
+    pub fn with_port(mut self, port: u16) -> Self {
+        self.settings.port = Some(port);
+        self
+    }
+
+    pub fn with_tls(mut self, tls: TlsConfig) -> Self {
+        self.settings.tls = Some(tls);
+        self
+    }
+
+    pub fn with_max_request_body_bytes(mut self, max_request_body_bytes: u64) -> Self {
+        self.settings.max_request_body_bytes = max_request_body_bytes;
+        self
+    }
+
+    pub fn with_max_batch_size(mut self, max_batch_size: usize) -> Self {
+        self.settings.max_batch_size = max_batch_size;
+        self
+    }
+
+    pub fn with_shutdown_timeout_seconds(mut self, shutdown_timeout_seconds: u64) -> Self {
+        self.settings.shutdown_timeout_seconds = shutdown_timeout_seconds;
+        self
+    }
+
+    pub fn with_enable_simulate_mode(mut self, enable_simulate_mode: bool) -> Self {
+        self.settings.enable_simulate_mode = enable_simulate_mode;
+        self
+    }
+
+    pub fn with_enable_policy_test_endpoint(mut self, enable_policy_test_endpoint: bool) -> Self {
+        self.settings.enable_policy_test_endpoint = enable_policy_test_endpoint;
+        self
+    }
+
+    pub fn with_verbose_policy_errors(mut self, verbose_policy_errors: bool) -> Self {
+        self.settings.verbose_policy_errors = verbose_policy_errors;
+        self
+    }
+
+    pub fn with_expose_openapi(mut self, expose_openapi: bool) -> Self {
+        self.settings.expose_openapi = expose_openapi;
+        self
+    }
+
+    pub fn with_admin_token(mut self, admin_token: SecretString) -> Self {
+        self.settings.admin_token = Some(admin_token);
+        self
+    }
+
+    pub fn with_enable_replay_prevention(mut self, enable_replay_prevention: bool) -> Self {
+        self.settings.enable_replay_prevention = enable_replay_prevention;
+        self
+    }
+
+    pub fn with_enable_response_cache(mut self, enable_response_cache: bool) -> Self {
+        self.settings.enable_response_cache = enable_response_cache;
+        self
+    }
+
+    pub fn with_response_cache_max_entries(mut self, response_cache_max_entries: usize) -> Self {
+        self.settings.response_cache_max_entries = response_cache_max_entries;
+        self
+    }
+
+    pub fn with_jwt_clock_skew_seconds(mut self, jwt_clock_skew_seconds: u64) -> Self {
+        self.settings.jwt_clock_skew_seconds = jwt_clock_skew_seconds;
+        self
+    }
+
+    pub fn with_providers(mut self, providers: Vec<OidcProvider>) -> Self {
+        self.settings.providers = providers;
+        self
+    }
+
+    pub fn with_oidc_startup_timeout_seconds(mut self, oidc_startup_timeout_seconds: u64) -> Self {
+        self.settings.oidc_startup_timeout_seconds = oidc_startup_timeout_seconds;
+        self
+    }
+
+    pub fn with_fail_on_provider_startup_error(
+        mut self,
+        fail_on_provider_startup_error: bool,
+    ) -> Self {
+        self.settings.fail_on_provider_startup_error = fail_on_provider_startup_error;
+        self
+    }
+
+    pub fn with_proxy(mut self, proxy: ProxySettings) -> Self {
+        self.settings.proxy = proxy;
+        self
+    }
+
+    pub fn with_oxide(mut self, oxide: SettingsOxide) -> Self {
+        self.settings.oxide = Some(oxide);
+        self
+    }
+
+    pub fn with_github(mut self, github: SettingsGitHub) -> Self {
+        self.settings.github = Some(github);
+        self
+    }
+
+    pub fn with_aws(mut self, aws: SettingsAws) -> Self {
+        self.settings.aws = Some(aws);
+        self
+    }
+
+    pub fn with_gcp(mut self, gcp: SettingsGcp) -> Self {
+        self.settings.gcp = Some(gcp);
+        self
+    }
+
+    /// Finish building the settings, failing if the audience or the policy path are missing. The
+    /// rest of the settings are not checked: call [`Settings::validate`] for that.
+    pub fn build(self) -> Result<Settings, SettingsValidationError> {
+        if self.settings.audiences.is_empty() {
+            return Err(SettingsValidationError::NoAudiences);
+        }
+        let policy_path = self
+            .policy_path
+            .ok_or(SettingsValidationError::NoPolicyPath)?;
+        Ok(Settings {
+            policy_path,
+            ..self.settings
+        })
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum SettingsValidationError {
+    #[error("at least one audience must be configured")]
+    NoAudiences,
+    #[error("the policy path must be configured")]
+    NoPolicyPath,
+    #[error("at least one provider must be configured")]
+    NoProviders,
+    #[error("the policy at {} can't be read", .0.display())]
+    PolicyUnreadable(PathBuf, #[source] std::io::Error),
+    #[error("the port must not be 0")]
+    InvalidPort,
+    #[error("the token of silo {0} at {} can't be read", .1.display())]
+    SiloTokenUnreadable(String, PathBuf, #[source] std::io::Error),
+    #[error("the silo token directory {} can't be read", .0.display())]
+    TokenDirectoryUnreadable(PathBuf, #[source] std::io::Error),
+    #[error("the GitHub App private key at {} can't be read", .0.display())]
+    GitHubPrivateKeyUnreadable(PathBuf, #[source] std::io::Error),
+    #[error("the GitHub App {0} has both private_key_path and private_key_env set")]
+    GitHubPrivateKeyConflict(String),
+    #[error("the GitHub App {0} has neither private_key_path nor private_key_env set")]
+    GitHubPrivateKeyMissing(String),
+    #[error("the environment variable {0} containing the GitHub App private key is not set")]
+    GitHubPrivateKeyEnvMissing(String),
+    #[error("the log directory {} is not a writable directory", .0.display())]
+    LogDirectoryNotWritable(PathBuf),
+}
+
+/// Whether files can be created in the directory. Permission bits don't account for ACLs,
+/// read-only mounts or the user oidcx runs as, so actually create (and remove) a file.
+fn is_writable_directory(directory: &Path) -> bool {
+    let probe = directory.join(format!(".oidcx-write-test-{}", std::process::id()));
+    match std::fs::File::create(&probe) {
+        Ok(_) => {
+            let _ = std::fs::remove_file(&probe);
+            true
+        }
+        Err(_) => false,
+    }
+}
+
+fn deserialize_audiences<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Audiences {
+        One(String),
+        Many(Vec<String>),
+    }
+
+    Ok(match Audiences::deserialize(deserializer)? {
+        Audiences::One(audience) => vec![audience],
+        Audiences::Many(audiences) => audiences,
+    })
+}
+
+// Requests only carry a JWT (usually a few KB even with many claims) and a small token request, so
+// 64 KiB leaves plenty of headroom while bounding how much JSON we parse for a single request.
+fn default_max_request_body_bytes() -> u64 {
+    64 * 1024
+}
+
+// Each request of a batch can call GitHub or a silo, so bound how much work one call can cause.
+fn default_max_batch_size() -> usize {
+    10
+}
+
+fn default_oidc_startup_timeout_seconds() -> u64 {
+    10
+}
+
+fn default_fail_on_provider_startup_error() -> bool {
+    true
+}
+
+// Kubernetes waits 30 seconds by default before killing a terminating pod.
+fn default_shutdown_timeout_seconds() -> u64 {
+    30
+}
+
+fn default_expose_openapi() -> bool {
+    true
+}
+
+fn default_response_cache_max_entries() -> usize {
+    1000
+}
+
+fn default_require_audience() -> bool {
+    true
+}
+
+fn default_jwt_clock_skew_seconds() -> u64 {
+    60
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct TlsConfig {
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct ProxySettings {
+    /// URL of the proxy used for plain HTTP requests.
+    #[serde(default)]
+    pub http: Option<String>,
+    /// URL of the proxy used for HTTPS requests.
+    #[serde(default)]
+    pub https: Option<String>,
+    /// Hosts, domains or IP ranges reached without going through the proxies.
+    #[serde(default)]
+    pub no_proxy: Option<Vec<String>>,
+}
+
+impl ProxySettings {
+    /// Route the requests of the client through the configured proxies. Without any configured
+    /// proxy the client keeps its default behavior, using the `HTTP_PROXY`, `HTTPS_PROXY` and
+    /// `NO_PROXY` environment variables.
+    pub fn apply(&self, mut builder: ClientBuilder) -> Result<ClientBuilder, reqwest::Error> {
+        let no_proxy = self
+            .no_proxy
+            .as_ref()
+            .and_then(|hosts| NoProxy::from_string(&hosts.join(",")));
+        if let Some(url) = &self.http {
+            builder = builder.proxy(Proxy::http(url)?.no_proxy(no_proxy.clone()));
+        }
+        if let Some(url) = &self.https {
+            builder = builder.proxy(Proxy::https(url)?.no_proxy(no_proxy));
+        }
+        Ok(builder)
+    }
 }
 
 #[derive(Debug, Deserialize)]
 pub struct SettingsOxide {
     #[serde(default = "default_max_duration")]
     pub max_duration: u32,
+    #[serde(default)]
+    pub min_duration: Option<u32>,
     #[serde(default = "default_allow_tokens_without_expiry")]
     pub allow_tokens_without_expiry: bool,
     #[serde(default)]
     pub silos: HashMap<String, PathBuf>,
+    /// Directory containing one `<host>.token` file per silo, in addition to `silos`.
+    #[serde(default)]
+    pub token_directory: Option<PathBuf>,
+    #[serde(default)]
+    pub step_timeouts: Option<OxideStepTimeouts>,
+    /// Timeout in seconds for each request made to the silos.
+    #[serde(default = "default_api_timeout_seconds")]
+    pub api_timeout_seconds: u64,
+    /// How long to cache the number of projects in each silo, in seconds.
+    #[serde(default = "default_project_count_cache_ttl")]
+    pub project_count_cache_ttl: u64,
+    /// Whether to refuse to start when a silo can't be reached, rather than logging a warning.
+    #[serde(default = "default_fail_on_unreachable_silo")]
+    pub fail_on_unreachable_silo: bool,
+    /// How many attempts to make to reach each silo at startup, waiting twice as long after each
+    /// failed attempt. Silos are always attempted at least once.
+    #[serde(default = "default_startup_retry_count")]
+    pub startup_retry_count: u32,
+}
+
+/// Timeouts in seconds for each request of the device flow used to generate Oxide tokens. Steps
+/// without a timeout can take as long as the silo takes to respond.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct OxideStepTimeouts {
+    #[serde(default)]
+    pub device_auth_request: Option<u64>,
+    #[serde(default)]
+    pub device_auth_confirm: Option<u64>,
+    #[serde(default)]
+    pub device_access_token: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SettingsGitHub {
+    /// App used for the organizations and users without a dedicated app.
+    #[serde(flatten, deserialize_with = "deserialize_default_app")]
+    pub default_app: Option<SettingsGitHubApp>,
+    /// Apps dedicated to a single organization or user, keyed by its name.
+    #[serde(default)]
+    pub organizations: HashMap<String, SettingsGitHubApp>,
+    /// How long to cache the metadata of repositories (like their visibility), in seconds.
+    #[serde(default)]
+    pub visibility_cache_ttl_seconds: Option<u64>,
+    /// Base URL of the GitHub API, only changed for GitHub Enterprise Server.
+    #[serde(default = "default_github_api_url")]
+    pub api_url: String,
+    /// Timeout in seconds for each request made to the GitHub API.
+    #[serde(default = "default_api_timeout_seconds")]
+    pub api_timeout_seconds: u64,
+    /// How many attempts to make for GitHub API requests that are rate limited or fail on
+    /// GitHub's side. Requests are always attempted at least once.
+    #[serde(default = "default_max_retries")]
+    pub max_retries: u32,
+    /// Permissions tokens can be requested with. All permissions are allowed if missing.
+    #[serde(default)]
+    pub allowed_permissions: Option<Vec<String>>,
+    /// Glob patterns the repositories tokens are requested for must match, like `org/*`.
+    #[serde(default)]
+    pub allowed_repository_patterns: Option<Vec<String>>,
+    /// Whether tokens can be requested for all the repositories of an organization or user.
+    #[serde(default)]
+    pub allow_all_repositories: bool,
+}
+
+impl SettingsGitHub {
+    pub fn apps(&self) -> impl Iterator<Item = &SettingsGitHubApp> {
+        self.default_app.iter().chain(self.organizations.values())
+    }
 }
 
 #[derive(Debug, Deserialize)]
 pub struct SettingsGitHubApp {
     pub client_id: String,
-    pub private_key_path: PathBuf,
+    /// Path to the PEM private key of the app.
+    #[serde(default)]
+    pub private_key_path: Option<PathBuf>,
+    /// Name of the environment variable containing the PEM private key of the app, as an
+    /// alternative to `private_key_path`.
+    #[serde(default)]
+    pub private_key_env: Option<String>,
+    /// ID of the app the credentials are expected to belong to, checked at startup.
+    #[serde(default)]
+    pub expected_app_id: Option<u64>,
+}
+
+/// Serde silently ignores errors in flattened optional structs, so a typo in the keys of the
+/// default app would disable it rather than fail. Read the keys individually instead, and only
+/// treat the app as missing if none of them are set.
+fn deserialize_default_app<'de, D>(deserializer: D) -> Result<Option<SettingsGitHubApp>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    struct DefaultAppKeys {
+        #[serde(default)]
+        client_id: Option<String>,
+        #[serde(default)]
+        private_key_path: Option<PathBuf>,
+        #[serde(default)]
+        private_key_env: Option<String>,
+        #[serde(default)]
+        expected_app_id: Option<u64>,
+    }
+
+    let keys = DefaultAppKeys::deserialize(deserializer)?;
+    match keys.client_id {
+        Some(client_id) => Ok(Some(SettingsGitHubApp {
+            client_id,
+            private_key_path: keys.private_key_path,
+            private_key_env: keys.private_key_env,
+            expected_app_id: keys.expected_app_id,
+        })),
+        None if keys.private_key_path.is_none()
+            && keys.private_key_env.is_none()
+            && keys.expected_app_id.is_none() =>
+        {
+            Ok(None)
+        }
+        None => Err(serde::de::Error::custom(
+            "the GitHub App in the [github] block is missing client_id",
+        )),
+    }
 }
 
 fn default_max_duration() -> u32 {
     3600
 }
 
+fn default_github_api_url() -> String {
+    "https://api.github.com".into()
+}
+
+fn default_max_retries() -> u32 {
+    3
+}
+
+fn default_api_timeout_seconds() -> u64 {
+    30
+}
+
+fn default_project_count_cache_ttl() -> u64 {
+    3600
+}
+
+fn default_fail_on_unreachable_silo() -> bool {
+    true
+}
+
+fn default_startup_retry_count() -> u32 {
+    3
+}
+
 fn default_allow_tokens_without_expiry() -> bool {
     false
 }
+
+#[derive(Debug, Deserialize)]
+pub struct SettingsAws {
+    #[serde(default = "default_sts_endpoint")]
+    pub sts_endpoint: String,
+    /// ARNs of the roles that can be assumed, keyed by the alias used in requests.
+    #[serde(default)]
+    pub roles: HashMap<String, String>,
+    /// Timeout in seconds for each request made to STS.
+    #[serde(default = "default_api_timeout_seconds")]
+    pub api_timeout_seconds: u64,
+}
+
+fn default_sts_endpoint() -> String {
+    "https://sts.amazonaws.com".into()
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SettingsGcp {
+    /// Path to the JSON key of the service account oidcx authenticates as.
+    pub credentials_path: PathBuf,
+    /// Timeout in seconds for each request made to the GCP APIs.
+    #[serde(default = "default_api_timeout_seconds")]
+    pub api_timeout_seconds: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::FileFormat;
+    use secrecy::ExposeSecret;
+    use serde::de::DeserializeOwned;
+
+    fn parse<T: DeserializeOwned>(toml: &str) -> Result<T, ConfigError> {
+        Config::builder()
+            .add_source(File::from_str(toml, FileFormat::Toml))
+            .build()?
+            .try_deserialize()
+    }
+
+    #[test]
+    fn github_default_app_is_optional() {
+        let github: SettingsGitHub = parse(
+            r#"
+            [organizations.oxidecomputer]
+            client_id = "Iv2BBBBBBBBBBBBBBBBB"
+            private_key_path = "oxidecomputer.pem"
+            "#,
+        )
+        .unwrap();
+        assert!(github.default_app.is_none());
+        assert_eq!(github.organizations.len(), 1);
+    }
+
+    #[test]
+    fn github_default_app_is_read_from_the_block() {
+        let github: SettingsGitHub = parse(
+            r#"
+            client_id = "Iv2AAAAAAAAAAAAAAAAA"
+            private_key_path = "private-key.pem"
+            expected_app_id = 123456
+            "#,
+        )
+        .unwrap();
+        let app = github.default_app.unwrap();
+        assert_eq!(app.client_id, "Iv2AAAAAAAAAAAAAAAAA");
+        assert_eq!(app.private_key_path, Some(PathBuf::from("private-key.pem")));
+        assert_eq!(app.expected_app_id, Some(123456));
+    }
+
+    #[test]
+    fn github_default_app_with_misspelled_client_id_fails() {
+        let err = parse::<SettingsGitHub>(
+            r#"
+            clientid = "Iv2AAAAAAAAAAAAAAAAA"
+            private_key_path = "private-key.pem"
+            "#,
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("missing client_id"), "{err}");
+    }
+
+    #[test]
+    fn builder_requires_an_audience() {
+        let err = Settings::builder()
+            .with_policy_path("policy.polar")
+            .build()
+            .unwrap_err();
+        assert!(matches!(err, SettingsValidationError::NoAudiences), "{err}");
+    }
+
+    #[test]
+    fn builder_requires_a_policy_path() {
+        let err = Settings::builder()
+            .with_audience("oidcx")
+            .build()
+            .unwrap_err();
+        assert!(
+            matches!(err, SettingsValidationError::NoPolicyPath),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn builder_uses_the_defaults_of_the_files() {
+        let built = Settings::builder()
+            .with_audience("oidcx")
+            .with_policy_path("policy.polar")
+            .with_max_batch_size(5)
+            .build()
+            .unwrap();
+        assert_eq!(built.audiences, vec!["oidcx".to_string()]);
+        assert_eq!(built.policy_path, PathBuf::from("policy.polar"));
+        assert_eq!(built.max_batch_size, 5);
+
+        let parsed: Settings = parse(
+            r#"
+            audiences = ["oidcx"]
+            policy_path = "policy.polar"
+            providers = []
+            "#,
+        )
+        .unwrap();
+        assert_eq!(built.require_audience, parsed.require_audience);
+        assert_eq!(built.max_request_body_bytes, parsed.max_request_body_bytes);
+        assert_eq!(
+            built.shutdown_timeout_seconds,
+            parsed.shutdown_timeout_seconds
+        );
+        assert_eq!(built.expose_openapi, parsed.expose_openapi);
+        assert_eq!(
+            built.response_cache_max_entries,
+            parsed.response_cache_max_entries
+        );
+        assert_eq!(built.jwt_clock_skew_seconds, parsed.jwt_clock_skew_seconds);
+        assert_eq!(
+            built.oidc_startup_timeout_seconds,
+            parsed.oidc_startup_timeout_seconds
+        );
+        assert_eq!(
+            built.fail_on_provider_startup_error,
+            parsed.fail_on_provider_startup_error
+        );
+    }
+
+    #[test]
+    fn log_directory_must_be_writable() {
+        let directory = std::env::temp_dir().join(format!(
+            "oidcx-logs-{}-{}",
+            std::process::id(),
+            rand::random::<u64>()
+        ));
+        assert!(!is_writable_directory(&directory));
+
+        std::fs::create_dir(&directory).unwrap();
+        assert!(is_writable_directory(&directory));
+        // The probe file is removed.
+        assert_eq!(std::fs::read_dir(&directory).unwrap().count(), 0);
+        std::fs::remove_dir(&directory).unwrap();
+    }
+
+    #[test]
+    fn environment_variables_override_the_files() {
+        let path = crate::test_util::temp_file(
+            "settings.toml",
+            r#"
+            audiences = ["oidcx"]
+            policy_path = "policy.polar"
+            admin_token = "from-file"
+
+            [github]
+            client_id = "Iv23file"
+            private_key_path = "github-app.pem"
+            "#,
+        );
+        let environment = Environment::with_prefix("OIDCX")
+            .separator("__")
+            .source(Some(
+                [
+                    ("OIDCX__ADMIN_TOKEN", "from-environment"),
+                    ("OIDCX__GITHUB__CLIENT_ID", "Iv23environment"),
+                ]
+                .into_iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+            ));
+
+        let settings =
+            Settings::from_sources(Some(vec![path.display().to_string()]), environment).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            settings.admin_token.unwrap().expose_secret(),
+            "from-environment"
+        );
+        let github = settings.github.unwrap();
+        assert_eq!(github.default_app.unwrap().client_id, "Iv23environment");
+        assert_eq!(settings.policy_path, PathBuf::from("policy.polar"));
+    }
+}
@@ -0,0 +1,314 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Helpers shared by the tests of the modules calling external services.
+
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use config::{Config, File, FileFormat};
+use dropshot::HttpServer;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::context::Context;
+use crate::server::{ServerConfig, server};
+use crate::settings::{Settings, SettingsBuilder, SettingsGitHub, SettingsOxide};
+use crate::token::github::GitHubTokens;
+
+/// Private key of the GitHub App used by the tests. Only ever used against a [`MockServer`].
+pub static GITHUB_APP_PRIVATE_KEY: &str = include_str!("../testdata/github-app.pem");
+
+/// Write `contents` to a new file in the temporary directory, returning its path.
+pub fn temp_file(name: &str, contents: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!(
+        "oidcx-{}-{}-{name}",
+        std::process::id(),
+        rand::random::<u64>()
+    ));
+    std::fs::write(&path, contents).unwrap();
+    path
+}
+
+/// Settings without any provider or token service, using `policy` as the policy.
+pub fn test_settings(policy: &str) -> SettingsBuilder {
+    Settings::builder()
+        .with_audience("oidcx")
+        .with_policy_path(temp_file("policy.polar", policy))
+}
+
+/// Serve the API on a random local port.
+pub async fn start_server(settings: Settings) -> HttpServer<Context> {
+    let request_body_max_bytes = settings.max_request_body_bytes as usize;
+    let context = Context::new(settings).await.unwrap();
+    server(ServerConfig {
+        context,
+        server_address: "127.0.0.1:0".parse().unwrap(),
+        tls_config: None,
+        request_body_max_bytes,
+    })
+    .unwrap()
+    .start()
+}
+
+/// Settings of an Oxide silo served by `server`.
+pub fn oxide_settings(server: &MockServer) -> SettingsOxide {
+    let token_path = temp_file("silo.token", "oxide-silo-credential");
+    let toml = format!(
+        r#"
+        [silos]
+        "{}" = "{}"
+        "#,
+        server.url(),
+        token_path.display(),
+    );
+    Config::builder()
+        .add_source(File::from_str(&toml, FileFormat::Toml))
+        .build()
+        .unwrap()
+        .try_deserialize()
+        .unwrap()
+}
+
+/// Settings of a GitHub App whose API is served by `server`, without retries.
+pub fn github_settings(server: &MockServer) -> SettingsGitHub {
+    let key_path = temp_file("github-app.pem", GITHUB_APP_PRIVATE_KEY);
+    let toml = format!(
+        r#"
+        client_id = "Iv23mock"
+        private_key_path = "{}"
+        api_url = "{}"
+        max_retries = 0
+        "#,
+        key_path.display(),
+        server.url(),
+    );
+    Config::builder()
+        .add_source(File::from_str(&toml, FileFormat::Toml))
+        .build()
+        .unwrap()
+        .try_deserialize()
+        .unwrap()
+}
+
+pub fn github_tokens(github: SettingsGitHub) -> GitHubTokens {
+    let settings = Settings::builder()
+        .with_audience("oidcx")
+        .with_policy_path("policy.polar")
+        .with_github(github)
+        .build()
+        .unwrap();
+    GitHubTokens::new(&settings).unwrap()
+}
+
+/// Answer the requests made to generate installation tokens for the repositories of `owner`.
+pub fn mock_github_installation(server: &MockServer, owner: &str) {
+    server.respond(
+        &format!("/orgs/{owner}/installation"),
+        MockResponse::json(200, serde_json::json!({ "id": 1 })),
+    );
+    server.respond(
+        "/app/installations/1/access_tokens",
+        MockResponse::json(201, serde_json::json!({ "token": "ghs_mock" })),
+    );
+}
+
+#[derive(Clone, Debug)]
+pub struct MockResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+}
+
+impl MockResponse {
+    pub fn json(status: u16, body: serde_json::Value) -> Self {
+        MockResponse {
+            status,
+            headers: vec![("content-type".into(), "application/json".into())],
+            body: body.to_string(),
+        }
+    }
+
+    pub fn empty(status: u16) -> Self {
+        MockResponse {
+            status,
+            headers: Vec::new(),
+            body: String::new(),
+        }
+    }
+
+    pub fn with_header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct MockRequest {
+    /// Path of the request, including the query string.
+    pub path: String,
+    /// Headers of the request, with lowercase names.
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+}
+
+impl MockRequest {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// Minimal HTTP/1.1 server answering with canned responses, to test the clients of external
+/// services without reaching them. Paths without a response get a 404.
+#[derive(Clone)]
+pub struct MockServer {
+    address: SocketAddr,
+    responses: Arc<Mutex<HashMap<String, VecDeque<MockResponse>>>>,
+    requests: Arc<Mutex<Vec<MockRequest>>>,
+    connections: Arc<AtomicUsize>,
+}
+
+impl MockServer {
+    pub async fn start() -> MockServer {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server = MockServer {
+            address: listener.local_addr().unwrap(),
+            responses: Arc::default(),
+            requests: Arc::default(),
+            connections: Arc::default(),
+        };
+
+        let handler = server.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let handler = handler.clone();
+                tokio::spawn(async move {
+                    let _ = handler.handle(stream).await;
+                });
+            }
+        });
+        server
+    }
+
+    /// Base URL of the server, without a trailing slash.
+    pub fn url(&self) -> String {
+        format!("http://{}", self.address)
+    }
+
+    /// Answer all requests for `path` with `response`. Paths including a query string only match
+    /// requests with the same query string, other paths match regardless of the query string.
+    pub fn respond(&self, path: &str, response: MockResponse) {
+        self.respond_sequence(path, vec![response]);
+    }
+
+    /// Answer the requests for `path` with each response in turn, repeating the last one.
+    pub fn respond_sequence(&self, path: &str, responses: Vec<MockResponse>) {
+        self.responses
+            .lock()
+            .unwrap()
+            .insert(path.into(), responses.into());
+    }
+
+    /// The requests received so far, in order.
+    pub fn requests(&self) -> Vec<MockRequest> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    /// How many requests were received for `path`, ignoring the query string.
+    pub fn request_count(&self, path: &str) -> usize {
+        self.requests()
+            .iter()
+            .filter(|request| request.path.split('?').next() == Some(path))
+            .count()
+    }
+
+    /// How many connections were opened to the server so far.
+    pub fn connection_count(&self) -> usize {
+        self.connections.load(Ordering::SeqCst)
+    }
+
+    fn response_for(&self, path: &str) -> MockResponse {
+        let mut responses = self.responses.lock().unwrap();
+        let without_query = path.split('?').next().unwrap_or(path);
+        let key = if responses.contains_key(path) {
+            path
+        } else {
+            without_query
+        };
+        let queue = responses.get_mut(key);
+        match queue {
+            Some(queue) if queue.len() > 1 => queue.pop_front().unwrap(),
+            Some(queue) => queue.front().cloned().unwrap(),
+            None => MockResponse::json(404, serde_json::json!({ "message": "Not Found" })),
+        }
+    }
+
+    /// Answer the requests sent on the connection until the client closes it.
+    async fn handle(&self, mut stream: TcpStream) -> std::io::Result<()> {
+        self.connections.fetch_add(1, Ordering::SeqCst);
+        let mut buffer = Vec::new();
+        let mut chunk = [0; 4096];
+        loop {
+            let head_length = loop {
+                if let Some(position) = buffer.windows(4).position(|window| window == b"\r\n\r\n") {
+                    break position + 4;
+                }
+                let read = stream.read(&mut chunk).await?;
+                if read == 0 {
+                    return Ok(());
+                }
+                buffer.extend_from_slice(&chunk[..read]);
+            };
+
+            let head = String::from_utf8_lossy(&buffer[..head_length]).into_owned();
+            let mut lines = head.lines();
+            let mut request_line = lines.next().unwrap_or_default().split(' ').skip(1);
+            let path = request_line.next().unwrap_or_default().to_string();
+            let headers = lines
+                .filter_map(|line| line.split_once(':'))
+                .map(|(name, value)| (name.trim().to_lowercase(), value.trim().to_string()))
+                .collect::<Vec<_>>();
+            let content_length = headers
+                .iter()
+                .find(|(name, _)| name == "content-length")
+                .and_then(|(_, value)| value.parse::<usize>().ok())
+                .unwrap_or(0);
+            while buffer.len() < head_length + content_length {
+                let read = stream.read(&mut chunk).await?;
+                if read == 0 {
+                    return Ok(());
+                }
+                buffer.extend_from_slice(&chunk[..read]);
+            }
+            let body = String::from_utf8_lossy(&buffer[head_length..head_length + content_length])
+                .into_owned();
+            buffer.drain(..head_length + content_length);
+
+            let response = self.response_for(&path);
+            self.requests.lock().unwrap().push(MockRequest {
+                path,
+                headers,
+                body,
+            });
+
+            let mut raw = format!(
+                "HTTP/1.1 {} Mock\r\ncontent-length: {}\r\n",
+                response.status,
+                response.body.len()
+            );
+            for (name, value) in &response.headers {
+                raw.push_str(&format!("{name}: {value}\r\n"));
+            }
+            raw.push_str("\r\n");
+            raw.push_str(&response.body);
+            stream.write_all(raw.as_bytes()).await?;
+        }
+    }
+}
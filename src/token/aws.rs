@@ -0,0 +1,360 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::endpoints::{SecretExtra, SecretToken, Token};
+use crate::settings::Settings;
+use reqwest::{Client, StatusCode};
+use schemars::JsonSchema;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::instrument;
+use zeroize::Zeroize;
+
+// Session names show up in CloudTrail, so make it clear where the credentials came from.
+static ROLE_SESSION_NAME: &str = "oidcx";
+
+#[derive(Clone, Debug, Deserialize, JsonSchema, Hash, PartialEq, Eq)]
+pub struct AwsTokenRequest {
+    /// Name of the role to assume, as defined in the oidcx configuration.
+    pub role_alias: String,
+    /// How long the credentials should be valid for. Defaults to the maximum allowed by the role.
+    #[serde(default)]
+    pub session_duration_seconds: Option<u32>,
+}
+
+#[derive(Clone, Debug)]
+pub struct AwsTokens {
+    state: Option<Arc<State>>,
+}
+
+impl AwsTokens {
+    pub fn new(settings: &Settings) -> Result<Self, AwsError> {
+        let proxy = &settings.proxy;
+        let Some(settings) = &settings.aws else {
+            return Ok(Self { state: None });
+        };
+
+        let client = proxy
+            .apply(Client::builder())
+            .map_err(AwsError::ProxyConfig)?
+            .timeout(Duration::from_secs(settings.api_timeout_seconds))
+            .build()
+            .map_err(AwsError::BuildClient)?;
+        Ok(Self {
+            state: Some(Arc::new(State {
+                client,
+                sts_endpoint: settings.sts_endpoint.clone(),
+                roles: settings.roles.clone(),
+            })),
+        })
+    }
+
+    /// Assume the role by presenting the caller's identity token to STS, which must be configured
+    /// to trust the caller's identity provider.
+    #[instrument(name = "token.generate", skip(self, web_identity_token))]
+    pub async fn get(
+        &self,
+        request: &AwsTokenRequest,
+        web_identity_token: &str,
+    ) -> Result<Token, AwsError> {
+        #[derive(Deserialize)]
+        #[serde(rename_all = "PascalCase")]
+        struct Response {
+            assume_role_with_web_identity_response: ResponseBody,
+        }
+
+        #[derive(Deserialize)]
+        #[serde(rename_all = "PascalCase")]
+        struct ResponseBody {
+            assume_role_with_web_identity_result: ResponseResult,
+        }
+
+        #[derive(Deserialize)]
+        #[serde(rename_all = "PascalCase")]
+        struct ResponseResult {
+            credentials: Credentials,
+        }
+
+        #[derive(Deserialize)]
+        #[serde(rename_all = "PascalCase")]
+        struct Credentials {
+            access_key_id: String,
+            secret_access_key: String,
+            session_token: String,
+            expiration: serde_json::Value,
+        }
+
+        #[derive(Deserialize)]
+        #[serde(rename_all = "PascalCase")]
+        struct ErrorResponse {
+            error: ErrorDetails,
+        }
+
+        #[derive(Deserialize)]
+        #[serde(rename_all = "PascalCase")]
+        struct ErrorDetails {
+            code: String,
+            message: String,
+        }
+
+        let state = self.state.as_ref().ok_or(AwsError::NotConfigured)?;
+        let role_arn = state
+            .roles
+            .get(&request.role_alias)
+            .ok_or_else(|| AwsError::RoleNotConfigured(request.role_alias.clone()))?;
+
+        let mut form = vec![
+            ("Action", "AssumeRoleWithWebIdentity".to_string()),
+            ("Version", "2011-06-15".to_string()),
+            ("RoleArn", role_arn.clone()),
+            ("RoleSessionName", ROLE_SESSION_NAME.to_string()),
+            ("WebIdentityToken", web_identity_token.to_string()),
+        ];
+        if let Some(duration) = request.session_duration_seconds {
+            form.push(("DurationSeconds", duration.to_string()));
+        }
+
+        // STS is a query API, which responds with XML unless JSON is explicitly requested.
+        let response = state
+            .client
+            .post(&state.sts_endpoint)
+            .header("accept", "application/json")
+            .form(&form)
+            .send()
+            .await
+            .map_err(AwsError::Http)?;
+        let status = response.status();
+        let mut text = response.text().await.map_err(AwsError::Http)?;
+
+        if !status.is_success() {
+            return Err(match serde_json::from_str(&text) {
+                Ok(ErrorResponse {
+                    error: ErrorDetails { code, message },
+                }) => AwsError::StsError(status, code, message),
+                // The body is only logged, as it could come from a proxy rather than STS itself.
+                Err(_) => {
+                    tracing::error!(%status, body = text, "STS returned an unexpected error");
+                    AwsError::StsError(
+                        status,
+                        "Unknown".into(),
+                        "the error response couldn't be parsed".into(),
+                    )
+                }
+            });
+        }
+
+        let response = serde_json::from_str::<Response>(&text);
+        text.zeroize();
+        let credentials = response
+            .map_err(AwsError::InvalidResponse)?
+            .assume_role_with_web_identity_response
+            .assume_role_with_web_identity_result
+            .credentials;
+
+        // The credentials are moved rather than copied, so that they're only left in the
+        // containers overwriting them once dropped.
+        let access_token = SecretToken::new(credentials.session_token);
+        let extra = serde_json::Map::from_iter([
+            (
+                "access_key_id".to_string(),
+                credentials.access_key_id.into(),
+            ),
+            (
+                "secret_access_key".to_string(),
+                credentials.secret_access_key.into(),
+            ),
+            (
+                "session_token".to_string(),
+                access_token.expose().to_string().into(),
+            ),
+            ("expiration".to_string(), credentials.expiration),
+        ]);
+        Ok(Token {
+            access_token,
+            extra: Some(SecretExtra::new(extra.into())),
+            expires_at: None,
+        })
+    }
+}
+
+#[derive(Debug)]
+struct State {
+    client: Client,
+    sts_endpoint: String,
+    roles: HashMap<String, String>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum AwsError {
+    #[error("AWS credentials are not configured for this instance of oidcx")]
+    NotConfigured,
+    #[error("The AWS role {0} is not configured in this instance of oidcx")]
+    RoleNotConfigured(String),
+    #[error("HTTP error")]
+    Http(#[source] reqwest::Error),
+    #[error("Failed to build the HTTP client")]
+    BuildClient(#[source] reqwest::Error),
+    #[error("The proxy configuration is invalid")]
+    ProxyConfig(#[source] reqwest::Error),
+    #[error("STS failed with status {0} ({1}): {2}")]
+    StsError(StatusCode, String, String),
+    #[error("Failed to parse the STS response")]
+    InvalidResponse(#[source] serde_json::Error),
+}
+
+impl AwsError {
+    pub fn safe_to_expose(&self) -> bool {
+        match self {
+            AwsError::Http(..)
+            | AwsError::BuildClient(..)
+            | AwsError::ProxyConfig(..)
+            | AwsError::InvalidResponse(..) => false,
+            AwsError::NotConfigured | AwsError::RoleNotConfigured(..) | AwsError::StsError(..) => {
+                true
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::{ProxySettings, SettingsAws};
+    use crate::test_util::{MockResponse, MockServer};
+
+    fn aws_settings(server: &MockServer) -> SettingsAws {
+        SettingsAws {
+            sts_endpoint: format!("{}/sts", server.url()),
+            roles: HashMap::from([(
+                "deploy".to_string(),
+                "arn:aws:iam::123456789012:role/deploy".to_string(),
+            )]),
+            api_timeout_seconds: 30,
+        }
+    }
+
+    fn aws_tokens(server: &MockServer) -> AwsTokens {
+        let settings = Settings::builder()
+            .with_audience("oidcx")
+            .with_policy_path("policy.polar")
+            .with_aws(aws_settings(server))
+            .build()
+            .unwrap();
+        AwsTokens::new(&settings).unwrap()
+    }
+
+    fn request() -> AwsTokenRequest {
+        AwsTokenRequest {
+            role_alias: "deploy".into(),
+            session_duration_seconds: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn credentials_are_returned_as_extra() {
+        let server = MockServer::start().await;
+        server.respond(
+            "/sts",
+            MockResponse::json(
+                200,
+                serde_json::json!({
+                    "AssumeRoleWithWebIdentityResponse": {
+                        "AssumeRoleWithWebIdentityResult": {
+                            "Credentials": {
+                                "AccessKeyId": "ASIAEXAMPLE",
+                                "SecretAccessKey": "secret",
+                                "SessionToken": "session",
+                                "Expiration": 1791985500,
+                            },
+                        },
+                    },
+                }),
+            ),
+        );
+
+        let token = aws_tokens(&server)
+            .get(&request(), "identity-token")
+            .await
+            .unwrap();
+        assert_eq!(token.access_token.expose(), "session");
+        assert_eq!(
+            *token.extra.unwrap().expose(),
+            serde_json::json!({
+                "access_key_id": "ASIAEXAMPLE",
+                "secret_access_key": "secret",
+                "session_token": "session",
+                "expiration": 1791985500,
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn sts_errors_are_reported() {
+        let server = MockServer::start().await;
+        server.respond(
+            "/sts",
+            MockResponse::json(
+                403,
+                serde_json::json!({
+                    "Error": {
+                        "Code": "AccessDenied",
+                        "Message": "Not authorized to perform sts:AssumeRoleWithWebIdentity",
+                    },
+                }),
+            ),
+        );
+
+        let err = aws_tokens(&server)
+            .get(&request(), "identity-token")
+            .await
+            .unwrap_err();
+        assert!(err.safe_to_expose());
+        assert_eq!(
+            err.to_string(),
+            "STS failed with status 403 Forbidden (AccessDenied): Not authorized to perform \
+             sts:AssumeRoleWithWebIdentity"
+        );
+    }
+
+    #[tokio::test]
+    async fn unparseable_sts_errors_are_not_exposed() {
+        let server = MockServer::start().await;
+        server.respond(
+            "/sts",
+            MockResponse {
+                status: 502,
+                headers: Vec::new(),
+                body: "<html>upstream proxy internals</html>".into(),
+            },
+        );
+
+        let err = aws_tokens(&server)
+            .get(&request(), "identity-token")
+            .await
+            .unwrap_err();
+        assert!(
+            !err.to_string().contains("upstream proxy internals"),
+            "{err}"
+        );
+    }
+
+    #[tokio::test]
+    async fn invalid_proxies_are_reported() {
+        let server = MockServer::start().await;
+        let settings = Settings::builder()
+            .with_audience("oidcx")
+            .with_policy_path("policy.polar")
+            .with_aws(aws_settings(&server))
+            .with_proxy(ProxySettings {
+                https: Some("not a proxy URL".into()),
+                ..Default::default()
+            })
+            .build()
+            .unwrap();
+        let err = AwsTokens::new(&settings).unwrap_err();
+        assert!(matches!(err, AwsError::ProxyConfig(..)), "{err}");
+    }
+}
@@ -0,0 +1,275 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::endpoints::{SecretExtra, SecretToken, Token};
+use crate::settings::Settings;
+use jsonwebtoken::{Algorithm, EncodingKey};
+use reqwest::{Client, RequestBuilder, StatusCode};
+use schemars::JsonSchema;
+use serde::Deserialize;
+use serde::de::DeserializeOwned;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::instrument;
+
+// oidcx only needs to impersonate other service accounts, which the IAM Credentials API requires
+// the cloud-platform scope for.
+static OIDCX_SCOPE: &str = "https://www.googleapis.com/auth/cloud-platform";
+
+/// Request for an access token impersonating a GCP service account.
+#[derive(Clone, Debug, Deserialize, JsonSchema, Hash, PartialEq, Eq)]
+pub struct GcpTokenRequest {
+    pub service_account_email: String,
+    pub scopes: Vec<String>,
+}
+
+#[derive(Clone, Debug)]
+pub struct GcpTokens {
+    state: Option<Arc<State>>,
+}
+
+impl GcpTokens {
+    pub fn new(settings: &Settings) -> Result<Self, GcpError> {
+        let proxy = &settings.proxy;
+        let Some(settings) = &settings.gcp else {
+            return Ok(Self { state: None });
+        };
+
+        #[derive(Deserialize)]
+        struct ServiceAccountKey {
+            client_email: String,
+            private_key: String,
+            token_uri: String,
+        }
+
+        let key = std::fs::read(&settings.credentials_path)
+            .map_err(|e| GcpError::ReadCredentials(settings.credentials_path.clone(), e))?;
+        let key: ServiceAccountKey =
+            serde_json::from_slice(&key).map_err(GcpError::ParseCredentials)?;
+        let client = proxy
+            .apply(Client::builder())
+            .map_err(GcpError::ProxyConfig)?
+            .timeout(Duration::from_secs(settings.api_timeout_seconds))
+            .build()
+            .map_err(GcpError::BuildClient)?;
+        Ok(Self {
+            state: Some(Arc::new(State {
+                client,
+                client_email: key.client_email,
+                private_key: EncodingKey::from_rsa_pem(key.private_key.as_bytes())
+                    .map_err(GcpError::LoadPrivateKey)?,
+                token_uri: key.token_uri,
+            })),
+        })
+    }
+
+    #[instrument(name = "token.generate", skip(self))]
+    pub async fn get(&self, request: &GcpTokenRequest) -> Result<Token, GcpError> {
+        #[derive(Deserialize)]
+        struct OAuthTokenResponse {
+            access_token: String,
+        }
+
+        #[derive(Deserialize)]
+        #[serde(rename_all = "camelCase")]
+        struct GenerateAccessTokenResponse {
+            access_token: String,
+            expire_time: String,
+        }
+
+        let state = self.state.as_ref().ok_or(GcpError::NotConfigured)?;
+        if request.scopes.is_empty() {
+            return Err(GcpError::NoScopes);
+        }
+        // The email ends up in the path of the impersonation request.
+        if !is_plain_service_account(&request.service_account_email) {
+            return Err(GcpError::InvalidServiceAccount(
+                request.service_account_email.clone(),
+            ));
+        }
+
+        // Authenticate as oidcx's own service account first.
+        let assertion = state.assertion()?;
+        let own_token: OAuthTokenResponse =
+            gcp_request(state.client.post(&state.token_uri).form(&[
+                ("grant_type", "urn:ietf:params:oauth:grant-type:jwt-bearer"),
+                ("assertion", assertion.as_str()),
+            ]))
+            .await?;
+
+        // Then impersonate the requested service account, which needs to grant oidcx's service
+        // account the Service Account Token Creator role.
+        let response: GenerateAccessTokenResponse = gcp_request(
+            state
+                .client
+                .post(format!(
+                    "https://iamcredentials.googleapis.com/v1/projects/-/serviceAccounts/{}:generateAccessToken",
+                    request.service_account_email
+                ))
+                .bearer_auth(own_token.access_token)
+                .json(&serde_json::json!({ "scope": request.scopes })),
+        )
+        .await?;
+
+        Ok(Token {
+            access_token: SecretToken::new(response.access_token),
+            extra: Some(SecretExtra::new(
+                serde_json::json!({ "expire_time": response.expire_time }),
+            )),
+            expires_at: None,
+        })
+    }
+}
+
+#[derive(Debug)]
+struct State {
+    client: Client,
+    client_email: String,
+    private_key: EncodingKey,
+    token_uri: String,
+}
+
+impl State {
+    /// Generate a JWT valid for 5 minutes, exchanged for an access token of oidcx's own service
+    /// account.
+    fn assertion(&self) -> Result<String, GcpError> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("we time travelled earlier than 1970, go collect your Nobel prize")
+            .as_secs();
+        jsonwebtoken::encode(
+            &jsonwebtoken::Header {
+                alg: Algorithm::RS256,
+                ..Default::default()
+            },
+            &serde_json::json!({
+                "iss": self.client_email,
+                "scope": OIDCX_SCOPE,
+                "aud": self.token_uri,
+                "iat": now - 10, // Handle skewed clocks.
+                "exp": now + 300,
+            }),
+            &self.private_key,
+        )
+        .map_err(GcpError::EncodeJwt)
+    }
+}
+
+/// Whether the service account is identified by a plain email or unique ID, which can't change the
+/// meaning of the URL it's included in.
+fn is_plain_service_account(email: &str) -> bool {
+    !email.is_empty()
+        && email
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '@' | '.' | '-' | '_'))
+}
+
+async fn gcp_request<T>(request: RequestBuilder) -> Result<T, GcpError>
+where
+    T: DeserializeOwned,
+{
+    let response = request.send().await.map_err(GcpError::Http)?;
+    let status = response.status();
+
+    if status.is_success() {
+        response.json().await.map_err(GcpError::Http)
+    } else {
+        let url = response.url().to_string();
+        let text = response.text().await.map_err(GcpError::Http)?;
+        Err(GcpError::GcpError(url, status, text))
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum GcpError {
+    #[error("GCP credentials are not configured for this instance of oidcx")]
+    NotConfigured,
+    #[error("failed to read the GCP credentials located at {}", .0.display())]
+    ReadCredentials(PathBuf, #[source] std::io::Error),
+    #[error("Failed to parse the GCP credentials")]
+    ParseCredentials(#[source] serde_json::Error),
+    #[error("Failed to load the GCP service account private key")]
+    LoadPrivateKey(#[source] jsonwebtoken::errors::Error),
+    #[error("Failed to encode the JWT")]
+    EncodeJwt(#[source] jsonwebtoken::errors::Error),
+    #[error("The requested token asked for no scopes")]
+    NoScopes,
+    #[error("{0} is not a valid service account email")]
+    InvalidServiceAccount(String),
+    #[error("Failed to build the HTTP client")]
+    BuildClient(#[source] reqwest::Error),
+    #[error("The proxy configuration is invalid")]
+    ProxyConfig(#[source] reqwest::Error),
+    #[error("HTTP error")]
+    Http(#[source] reqwest::Error),
+    #[error("Request to {0} failed with status {1}: {2}")]
+    GcpError(String, StatusCode, String),
+}
+
+impl GcpError {
+    pub fn safe_to_expose(&self) -> bool {
+        match self {
+            GcpError::ReadCredentials(..)
+            | GcpError::ParseCredentials(..)
+            | GcpError::LoadPrivateKey(..)
+            | GcpError::EncodeJwt(..)
+            | GcpError::BuildClient(..)
+            | GcpError::ProxyConfig(..)
+            | GcpError::Http(..) => false,
+            GcpError::NotConfigured
+            | GcpError::NoScopes
+            | GcpError::InvalidServiceAccount(..)
+            | GcpError::GcpError(..) => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::SettingsGcp;
+    use crate::test_util::{GITHUB_APP_PRIVATE_KEY, MockServer, temp_file};
+
+    #[tokio::test]
+    async fn service_accounts_must_be_plain_emails() {
+        let server = MockServer::start().await;
+        let credentials = serde_json::json!({
+            "client_email": "oidcx@example.iam.gserviceaccount.com",
+            "private_key": GITHUB_APP_PRIVATE_KEY,
+            "token_uri": format!("{}/token", server.url()),
+        });
+        let settings = Settings::builder()
+            .with_audience("oidcx")
+            .with_policy_path("policy.polar")
+            .with_gcp(SettingsGcp {
+                credentials_path: temp_file("gcp.json", &credentials.to_string()),
+                api_timeout_seconds: 5,
+            })
+            .build()
+            .unwrap();
+        let gcp = GcpTokens::new(&settings).unwrap();
+
+        for email in [
+            "../../../v1/projects/-/serviceAccounts/admin@example.iam.gserviceaccount.com",
+            "deploy@example.iam.gserviceaccount.com:signJwt?",
+            "deploy%40example.iam.gserviceaccount.com",
+            "",
+        ] {
+            let request = GcpTokenRequest {
+                service_account_email: email.into(),
+                scopes: vec!["https://www.googleapis.com/auth/cloud-platform".into()],
+            };
+            let err = gcp.get(&request).await.unwrap_err();
+            assert!(matches!(err, GcpError::InvalidServiceAccount(_)), "{err}");
+        }
+        // Rejected before oidcx even authenticates.
+        assert!(server.requests().is_empty());
+
+        assert!(is_plain_service_account(
+            "deploy@example.iam.gserviceaccount.com"
+        ));
+        assert!(is_plain_service_account("112233445566778899000"));
+    }
+}
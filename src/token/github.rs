@@ -2,50 +2,352 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
-use crate::endpoints::Token;
-use crate::settings::Settings;
+use crate::endpoints::{SecretToken, Token};
+use crate::settings::{Settings, SettingsGitHubApp};
+use base64::Engine;
+use base64::prelude::BASE64_STANDARD;
+use chrono::{DateTime, Utc};
+use glob::{MatchOptions, Pattern};
 use jsonwebtoken::{Algorithm, EncodingKey};
-use reqwest::{Client, RequestBuilder, StatusCode};
+use reqwest::{Client, RequestBuilder, Response, StatusCode};
 use schemars::JsonSchema;
-use serde::Deserialize;
 use serde::de::DeserializeOwned;
-use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
 use std::path::PathBuf;
-use std::sync::Arc;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::instrument;
+use zeroize::Zeroize;
 
 static USER_AGENT: &str = "https://github.com/oxidecomputer/oidcx";
 
+// GitHub Packages accepts installation tokens over HTTP Basic auth with any username, but this
+// is the one GitHub uses in its own documentation for app tokens.
+static PACKAGES_USERNAME: &str = "x-access-token";
+
+static PERMISSION_LEVELS: &[&str] = &["read", "write", "admin"];
+// GitHub grants read access to the metadata of the repositories to every installation token, and
+// oidcx relies on it to fetch the repository metadata exposed to the policy.
+static ALWAYS_ALLOWED_PERMISSION: &str = "metadata";
+
+// Repository names are case insensitive, and `*` must not match across the `org/name` separator.
+static REPOSITORY_MATCH_OPTIONS: MatchOptions = MatchOptions {
+    case_sensitive: false,
+    require_literal_separator: true,
+    require_literal_leading_dot: false,
+};
+
+// Warn before running out of requests, while there is still time to investigate.
+static RATE_LIMIT_WARNING_THRESHOLD: u64 = 100;
+
+static INITIAL_RETRY_DELAY: Duration = Duration::from_millis(500);
+// Waiting longer than this would likely exceed the caller's own timeout, so fail instead.
+static MAX_RETRY_WAIT: Duration = Duration::from_secs(60);
+
 #[derive(Clone, Debug, Deserialize, JsonSchema, Hash, PartialEq, Eq)]
 pub struct GitHubTokenRequest {
+    /// Repositories the token can access, in the `org/name` format. Must be empty when requesting
+    /// access to all repositories.
+    #[serde(default)]
     pub repositories: Vec<String>,
     pub permissions: Vec<String>,
+    /// Whether the token can access the selected repositories (the default), or all the
+    /// repositories the app is installed on in `owner`.
+    #[serde(default)]
+    pub repository_selection: Option<RepositorySelection>,
+    /// Organization or user to grant access to all the repositories of.
+    #[serde(default)]
+    pub owner: Option<String>,
+}
+
+impl GitHubTokenRequest {
+    pub fn all_repositories(&self) -> bool {
+        self.repository_selection == Some(RepositorySelection::All)
+    }
+
+    /// Ensure the owner is set exactly when requesting all repositories, and that no repositories
+    /// are listed in that case.
+    pub fn check_selection(&self) -> Result<(), GitHubTokenError> {
+        if self.all_repositories() {
+            if self.owner.is_none() || !self.repositories.is_empty() {
+                return Err(GitHubTokenError::InvalidAllRepositoriesRequest);
+            }
+        } else if self.owner.is_some() {
+            return Err(GitHubTokenError::OwnerWithSelectedRepositories);
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, JsonSchema, Hash, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RepositorySelection {
+    Selected,
+    All,
+}
+
+/// Request for a token to access the GitHub Packages Maven or Gradle registries of the given
+/// repositories.
+#[derive(Clone, Debug, Deserialize, JsonSchema, Hash, PartialEq, Eq)]
+pub struct GitHubPackagesTokenRequest {
+    pub repositories: Vec<String>,
+    /// Whether the token should be able to publish packages, rather than only download them.
+    #[serde(default)]
+    pub write: bool,
+}
+
+impl GitHubPackagesTokenRequest {
+    pub fn to_token_request(&self) -> GitHubTokenRequest {
+        GitHubTokenRequest {
+            repositories: self.repositories.clone(),
+            permissions: vec![if self.write {
+                "packages:write".into()
+            } else {
+                "packages:read".into()
+            }],
+            repository_selection: None,
+            owner: None,
+        }
+    }
+}
+
+/// Details about a repository exposed to the policy, as reported by GitHub.
+#[derive(Clone, Debug, Deserialize)]
+pub struct RepositoryMetadata {
+    pub visibility: String,
+    pub archived: bool,
+    pub default_branch: String,
+    #[serde(default)]
+    pub topics: Vec<String>,
+}
+
+/// Details about a configured GitHub App, as reported by GitHub.
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct GitHubAppMetadata {
+    pub id: u64,
+    pub client_id: String,
+    pub name: String,
+    /// Login of the organization or user owning the app.
+    pub owner: String,
+    /// Permissions granted to the app, like `contents: read`.
+    pub permissions: BTreeMap<String, String>,
+}
+
+/// Installation of a configured app on an organization or user.
+#[derive(Debug, Serialize, JsonSchema)]
+pub struct Installation {
+    pub id: u64,
+    /// Client ID of the app installed.
+    pub app_client_id: String,
+    pub account_login: String,
+    /// Either `Organization` or `User`.
+    pub account_type: String,
+    /// Either `all` or `selected`, depending on whether the app can access all repositories.
+    pub repository_selection: String,
+}
+
+/// The rate limit of a credential, as last reported by GitHub.
+#[derive(Clone, Debug, Serialize, JsonSchema)]
+pub struct RateLimitState {
+    pub limit: u64,
+    pub remaining: u64,
+    /// Unix timestamp at which the remaining requests are reset to the limit.
+    pub reset: u64,
+}
+
+/// Credentials in the format Maven and Gradle expect to authenticate with GitHub Packages.
+#[derive(Debug)]
+pub struct PackagesToken {
+    pub username: String,
+    pub password: SecretToken,
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+impl PackagesToken {
+    /// Encode the credentials as the value of an HTTP Basic `Authorization` header.
+    pub fn basic_auth(&self) -> SecretToken {
+        let mut credentials = format!("{}:{}", self.username, self.password.expose());
+        let encoded = BASE64_STANDARD.encode(&credentials);
+        credentials.zeroize();
+        SecretToken::new(encoded)
+    }
 }
 
 #[derive(Debug)]
 struct State {
     client: Client,
+    /// Base URL of the API, without a trailing slash.
+    api_url: String,
+    default_app: Option<AppCredentials>,
+    // Keys are lowercase, as GitHub organization and user names are case insensitive.
+    namespace_apps: HashMap<String, AppCredentials>,
+    max_retries: u32,
+    allowed_permissions: Option<Vec<String>>,
+    allowed_repository_patterns: Option<Vec<Pattern>>,
+    allow_all_repositories: bool,
+    rate_limits: Mutex<HashMap<String, RateLimitState>>,
+}
+
+impl State {
+    fn record_rate_limit(&self, bucket: &str, response: &Response) {
+        let header = |name: &str| -> Option<u64> {
+            response.headers().get(name)?.to_str().ok()?.parse().ok()
+        };
+        let (Some(limit), Some(remaining), Some(reset)) = (
+            header("x-ratelimit-limit"),
+            header("x-ratelimit-remaining"),
+            header("x-ratelimit-reset"),
+        ) else {
+            return;
+        };
+
+        if remaining < RATE_LIMIT_WARNING_THRESHOLD {
+            tracing::warn!(
+                bucket,
+                limit,
+                remaining,
+                reset,
+                "The GitHub rate limit is almost exhausted"
+            );
+        }
+        self.rate_limits.lock().unwrap().insert(
+            bucket.into(),
+            RateLimitState {
+                limit,
+                remaining,
+                reset,
+            },
+        );
+    }
+
+    fn apps(&self) -> impl Iterator<Item = &AppCredentials> {
+        self.default_app.iter().chain(self.namespace_apps.values())
+    }
+
+    fn app_for(&self, namespace: &str) -> Result<&AppCredentials, GitHubTokenError> {
+        self.namespace_apps
+            .get(&namespace.to_lowercase())
+            .or(self.default_app.as_ref())
+            .ok_or_else(|| GitHubTokenError::NoAppForOrg(namespace.into()))
+    }
+}
+
+#[derive(Debug)]
+struct AppCredentials {
     client_id: String,
     private_key: EncodingKey,
 }
 
-#[derive(Clone, Debug)]
+impl AppCredentials {
+    fn load(settings: &SettingsGitHubApp) -> Result<Self, GitHubTokenError> {
+        let private_key = match (&settings.private_key_path, &settings.private_key_env) {
+            (Some(path), None) => std::fs::read(path)
+                .map_err(|e| GitHubTokenError::ReadPrivateKey(path.clone(), e))?,
+            (None, Some(var)) => {
+                // The error is dropped, as it might contain the key itself if it's not valid UTF-8.
+                let key = std::env::var(var)
+                    .map_err(|_| GitHubTokenError::ReadPrivateKeyEnv(var.clone()))?;
+                // Multiline values are awkward to pass in some environments, so also accept the
+                // newlines escaped as `\n`.
+                let key = if key.contains('\n') {
+                    key
+                } else {
+                    key.replace("\\n", "\n")
+                };
+                key.trim().as_bytes().to_vec()
+            }
+            _ => {
+                return Err(GitHubTokenError::PrivateKeySource(
+                    settings.client_id.clone(),
+                ));
+            }
+        };
+        Ok(AppCredentials {
+            client_id: settings.client_id.clone(),
+            private_key: EncodingKey::from_rsa_pem(&private_key)
+                .map_err(GitHubTokenError::LoadPrivateKey)?,
+        })
+    }
+
+    /// Name under which the rate limit of the requests authenticated as the app is recorded.
+    fn rate_limit_bucket(&self) -> String {
+        format!("app:{}", self.client_id)
+    }
+
+    /// Generate a JWT valid for 5 minutes, used to authenticate with GitHub as the app.
+    fn jwt(&self) -> Result<String, GitHubTokenError> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("we time travelled earlier than 1970, go collect your Nobel prize")
+            .as_secs();
+        jsonwebtoken::encode(
+            &jsonwebtoken::Header {
+                alg: Algorithm::RS256,
+                ..Default::default()
+            },
+            &serde_json::json!({
+                "iss": self.client_id,
+                "iat": now - 10, // Handle skewed clocks.
+                "exp": now + 300,
+            }),
+            &self.private_key,
+        )
+        .map_err(GitHubTokenError::EncodeJwt)
+    }
+}
+
+/// The default instance is not configured, and fails to generate any token.
+#[derive(Clone, Debug, Default)]
 pub struct GitHubTokens {
     state: Option<Arc<State>>,
 }
 
 impl GitHubTokens {
     pub fn new(settings: &Settings) -> Result<Self, GitHubTokenError> {
+        let proxy = &settings.proxy;
         if let Some(settings) = &settings.github {
-            let private_key = std::fs::read(&settings.private_key_path).map_err(|e| {
-                GitHubTokenError::ReadPrivateKey(settings.private_key_path.clone(), e)
-            })?;
+            let default_app = settings
+                .default_app
+                .as_ref()
+                .map(AppCredentials::load)
+                .transpose()?;
+            let namespace_apps = settings
+                .organizations
+                .iter()
+                .map(|(namespace, app)| Ok((namespace.to_lowercase(), AppCredentials::load(app)?)))
+                .collect::<Result<HashMap<_, _>, GitHubTokenError>>()?;
+            let allowed_repository_patterns = settings
+                .allowed_repository_patterns
+                .as_ref()
+                .map(|patterns| {
+                    patterns
+                        .iter()
+                        .map(|pattern| {
+                            Pattern::new(pattern).map_err(|e| {
+                                GitHubTokenError::InvalidRepositoryPattern(pattern.clone(), e)
+                            })
+                        })
+                        .collect::<Result<Vec<_>, _>>()
+                })
+                .transpose()?;
+            let client = proxy
+                .apply(Client::builder())
+                .map_err(GitHubTokenError::ProxyConfig)?
+                .timeout(Duration::from_secs(settings.api_timeout_seconds))
+                .build()
+                .map_err(GitHubTokenError::BuildClient)?;
             Ok(GitHubTokens {
                 state: Some(Arc::new(State {
-                    client: Client::new(),
-                    client_id: settings.client_id.clone(),
-                    private_key: EncodingKey::from_rsa_pem(&private_key)
-                        .map_err(GitHubTokenError::LoadPrivateKey)?,
+                    client,
+                    api_url: settings.api_url.trim_end_matches('/').to_string(),
+                    default_app,
+                    namespace_apps,
+                    max_retries: settings.max_retries,
+                    allowed_permissions: settings.allowed_permissions.clone(),
+                    allowed_repository_patterns,
+                    allow_all_repositories: settings.allow_all_repositories,
+                    rate_limits: Mutex::new(HashMap::new()),
                 })),
             })
         } else {
@@ -53,28 +355,10 @@ impl GitHubTokens {
         }
     }
 
+    #[instrument(name = "token.generate", skip(self))]
     pub async fn get(&self, request: &GitHubTokenRequest) -> Result<Token, GitHubTokenError> {
         let state = self.state.as_ref().ok_or(GitHubTokenError::NoCredentials)?;
 
-        // Generate a JWT valid for 5 minutes, used to authenticate with GitHub.
-        let now = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .expect("we time travelled earlier than 1970, go collect your Nobel prize")
-            .as_secs();
-        let jwt = jsonwebtoken::encode(
-            &jsonwebtoken::Header {
-                alg: Algorithm::RS256,
-                ..Default::default()
-            },
-            &serde_json::json!({
-                "iss": state.client_id,
-                "iat": now - 10, // Handle skewed clocks.
-                "exp": now + 300,
-            }),
-            &state.private_key,
-        )
-        .map_err(GitHubTokenError::EncodeJwt)?;
-
         // We need all repositories to belong to a single namespace (user or organization), as we
         // need to assume the role of the installation of the app in that namespace. While we are
         // at it, we also collect the repository names without the namespace, as the API requires.
@@ -92,13 +376,58 @@ impl GitHubTokens {
                 _ => return Err(GitHubTokenError::NotAGitHubRepository(repo.clone())),
             }
         }
-        let namespace = found_namespace.ok_or(GitHubTokenError::NoRepositories)?;
+        request.check_selection()?;
+        // The owner is only set when requesting all repositories.
+        let namespace = match &request.owner {
+            Some(owner) => {
+                if !state.allow_all_repositories {
+                    return Err(GitHubTokenError::AllRepositoriesNotAllowed);
+                }
+                owner.as_str()
+            }
+            None => found_namespace.ok_or(GitHubTokenError::NoRepositoriesWithSelectedMode)?,
+        };
+
+        // Defense in depth against policies granting access to more repositories than intended.
+        if let Some(patterns) = &state.allowed_repository_patterns {
+            if request.all_repositories() {
+                // Only a pattern covering the whole namespace allows all of its repositories.
+                let all = format!("{namespace}/*");
+                if !patterns
+                    .iter()
+                    .any(|pattern| pattern.as_str().eq_ignore_ascii_case(&all))
+                {
+                    return Err(GitHubTokenError::RepositoryNotAllowed(all));
+                }
+            }
+            for repo in &request.repositories {
+                if !patterns
+                    .iter()
+                    .any(|pattern| pattern.matches_with(repo, REPOSITORY_MATCH_OPTIONS))
+                {
+                    return Err(GitHubTokenError::RepositoryNotAllowed(repo.clone()));
+                }
+            }
+        }
 
         // Convert the permission:level syntax in the format GitHub expects.
         let mut permissions = HashMap::new();
         for permission in &request.permissions {
             match permission.split_once(':') {
                 Some((name, level)) if !name.contains('/') => {
+                    // Catch mistakes before GitHub rejects them with a less helpful error.
+                    if !PERMISSION_LEVELS.contains(&level) {
+                        return Err(GitHubTokenError::InvalidPermissionLevel {
+                            permission: name.into(),
+                            level: level.into(),
+                        });
+                    }
+                    if let Some(allowed) = &state.allowed_permissions
+                        && name != ALWAYS_ALLOWED_PERMISSION
+                        && !allowed.iter().any(|allowed| allowed == name)
+                    {
+                        return Err(GitHubTokenError::UnknownPermission(name.into()));
+                    }
                     if let Some(_) = permissions.insert(name, level) {
                         return Err(GitHubTokenError::DuplicatePermission(name.into()));
                     }
@@ -107,6 +436,12 @@ impl GitHubTokens {
             }
         }
 
+        // Each organization or user can have its own app, so we can only authenticate once we know
+        // which namespace the repositories belong to.
+        let app = state.app_for(namespace)?;
+        let jwt = app.jwt()?;
+        let bucket = app.rate_limit_bucket();
+
         // Get the installation ID. We look for the namespace in both the users and the
         // organizations, to gracefully handle when the app is installed on a personal account
         // rather than an organization.
@@ -115,10 +450,10 @@ impl GitHubTokens {
             let response = github_request::<InstallationResponse>(
                 state
                     .client
-                    .get(format!(
-                        "https://api.github.com/{kind}/{namespace}/installation"
-                    ))
+                    .get(format!("{}/{kind}/{namespace}/installation", state.api_url))
                     .bearer_auth(&jwt),
+                state,
+                &bucket,
             )
             .await;
             match response {
@@ -135,42 +470,181 @@ impl GitHubTokens {
             state
                 .client
                 .post(format!(
-                    "https://api.github.com/app/installations/{installation}/access_tokens"
+                    "{}/app/installations/{installation}/access_tokens",
+                    state.api_url
                 ))
                 .bearer_auth(&jwt)
-                .json(&serde_json::json!({
-                    "repositories": repos_without_namespace,
-                    "permissions": permissions,
-                })),
+                .json(&if request.all_repositories() {
+                    // Omitting the repositories grants access to all of them.
+                    serde_json::json!({ "permissions": permissions })
+                } else {
+                    serde_json::json!({
+                        "repositories": repos_without_namespace,
+                        "permissions": permissions,
+                    })
+                }),
+            state,
+            &bucket,
         )
-        .await?;
+        .await
+        .map_err(|err| match err {
+            GitHubTokenError::GitHubError(_, StatusCode::FORBIDDEN, message)
+                if message.contains("suspended") =>
+            {
+                tracing::warn!(
+                    namespace,
+                    installation,
+                    "The GitHub App installation is suspended, unsuspend it in the settings of \
+                     the organization or user to generate tokens again"
+                );
+                GitHubTokenError::InstallationSuspended {
+                    namespace: namespace.into(),
+                }
+            }
+            err => err,
+        })?;
 
         Ok(Token {
-            access_token: access_token.token,
+            access_token: SecretToken::new(access_token.token),
+            extra: None,
+            expires_at: access_token.expires_at,
         })
     }
 
-    pub async fn repository_visibility(&self, repo: &str) -> Result<String, GitHubTokenError> {
+    pub async fn get_packages_token(
+        &self,
+        request: &GitHubPackagesTokenRequest,
+    ) -> Result<PackagesToken, GitHubTokenError> {
+        let token = self.get(&request.to_token_request()).await?;
+        Ok(PackagesToken {
+            username: PACKAGES_USERNAME.into(),
+            password: token.access_token,
+            expires_at: token.expires_at,
+        })
+    }
+
+    /// Retrieve the details of every configured app, to verify the credentials belong to the
+    /// expected apps.
+    pub async fn get_app_metadata(&self) -> Result<Vec<GitHubAppMetadata>, GitHubTokenError> {
+        #[derive(serde::Deserialize)]
+        struct App {
+            id: u64,
+            client_id: String,
+            name: String,
+            owner: Owner,
+            permissions: BTreeMap<String, String>,
+        }
+
+        #[derive(serde::Deserialize)]
+        struct Owner {
+            login: String,
+        }
+
+        let state = self.state.as_ref().ok_or(GitHubTokenError::NoCredentials)?;
+        let mut metadata = Vec::new();
+        for app in state.apps() {
+            let response = github_request::<App>(
+                state
+                    .client
+                    .get(format!("{}/app", state.api_url))
+                    .bearer_auth(app.jwt()?),
+                state,
+                &app.rate_limit_bucket(),
+            )
+            .await?;
+            metadata.push(GitHubAppMetadata {
+                id: response.id,
+                client_id: response.client_id,
+                name: response.name,
+                owner: response.owner.login,
+                permissions: response.permissions,
+            });
+        }
+        Ok(metadata)
+    }
+
+    /// The rate limits last reported by GitHub, keyed by the credential they apply to: either
+    /// `app:<client ID>` for requests authenticated as an app, or `installation:<owner>` for
+    /// requests authenticated as the installation of an app on an organization or user.
+    pub fn rate_limits(&self) -> Result<BTreeMap<String, RateLimitState>, GitHubTokenError> {
+        let state = self.state.as_ref().ok_or(GitHubTokenError::NoCredentials)?;
+        Ok(state
+            .rate_limits
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(bucket, rate_limit)| (bucket.clone(), rate_limit.clone()))
+            .collect())
+    }
+
+    /// List the installations of every configured app, to verify they are installed on the
+    /// expected organizations.
+    pub async fn list_installations(&self) -> Result<Vec<Installation>, GitHubTokenError> {
         #[derive(serde::Deserialize)]
-        struct Repo {
-            visibility: String,
+        struct InstallationItem {
+            id: u64,
+            account: Account,
+            repository_selection: String,
+        }
+
+        #[derive(serde::Deserialize)]
+        struct Account {
+            login: String,
+            r#type: String,
         }
 
+        let state = self.state.as_ref().ok_or(GitHubTokenError::NoCredentials)?;
+        let mut installations = Vec::new();
+        for app in state.apps() {
+            let jwt = app.jwt()?;
+            let mut url = Some(format!("{}/app/installations?per_page=100", state.api_url));
+            while let Some(page_url) = url {
+                let response = github_response(
+                    state.client.get(&page_url).bearer_auth(&jwt),
+                    state,
+                    &app.rate_limit_bucket(),
+                )
+                .await?;
+                url = next_page(&response);
+                let items: Vec<InstallationItem> =
+                    response.json().await.map_err(GitHubTokenError::from_http)?;
+                installations.extend(items.into_iter().map(|item| Installation {
+                    id: item.id,
+                    app_client_id: app.client_id.clone(),
+                    account_login: item.account.login,
+                    account_type: item.account.r#type,
+                    repository_selection: item.repository_selection,
+                }));
+            }
+        }
+        Ok(installations)
+    }
+
+    pub async fn repository_metadata(
+        &self,
+        repo: &str,
+    ) -> Result<RepositoryMetadata, GitHubTokenError> {
         let state = self.state.as_ref().ok_or(GitHubTokenError::NoCredentials)?;
         let token = self
             .get(&GitHubTokenRequest {
                 repositories: vec![repo.into()],
                 permissions: vec!["metadata:read".into()],
+                repository_selection: None,
+                owner: None,
             })
             .await?;
-        Ok(github_request::<Repo>(
+        github_request::<RepositoryMetadata>(
             state
                 .client
-                .get(format!("https://api.github.com/repos/{repo}"))
-                .bearer_auth(token.access_token),
+                .get(format!("{}/repos/{repo}", state.api_url))
+                .bearer_auth(token.access_token.expose()),
+            state,
+            &format!(
+                "installation:{}",
+                repo.split('/').next().unwrap_or(repo).to_lowercase()
+            ),
         )
-        .await?
-        .visibility)
+        .await
     }
 }
 
@@ -182,29 +656,84 @@ struct InstallationResponse {
 #[derive(serde::Deserialize)]
 struct AccessTokenResponse {
     token: String,
+    #[serde(default)]
+    expires_at: Option<DateTime<Utc>>,
 }
 
-async fn github_request<T>(request: RequestBuilder) -> Result<T, GitHubTokenError>
+async fn github_request<T>(
+    request: RequestBuilder,
+    state: &State,
+    bucket: &str,
+) -> Result<T, GitHubTokenError>
 where
     T: DeserializeOwned,
 {
+    github_response(request, state, bucket)
+        .await?
+        .json()
+        .await
+        .map_err(GitHubTokenError::from_http)
+}
+
+/// Send the request, returning the response if it was successful. The rate limit reported by
+/// GitHub is recorded under `bucket`, naming the credential the request is authenticated with.
+async fn github_response(
+    request: RequestBuilder,
+    state: &State,
+    bucket: &str,
+) -> Result<Response, GitHubTokenError> {
     #[derive(serde::Deserialize)]
     struct GitHubError {
         message: String,
     }
 
-    let response = request
-        .header("user-agent", USER_AGENT)
-        .send()
-        .await
-        .map_err(GitHubTokenError::Http)?;
+    // Retry rate limited requests and server errors, waiting longer after each attempt. Other
+    // errors are caused by the request itself, and would fail again.
+    let mut request = request;
+    let mut delay = INITIAL_RETRY_DELAY;
+    let mut attempt = 1;
+    let response = loop {
+        let next_request = request.try_clone();
+        let response = request
+            .header("user-agent", USER_AGENT)
+            .send()
+            .await
+            .map_err(GitHubTokenError::from_http)?;
+        state.record_rate_limit(bucket, &response);
+        let status = response.status();
+        let retry_after = retry_after(&response);
+        let rate_limited = is_rate_limited(&response, retry_after);
+        if !rate_limited && !status.is_server_error() {
+            break response;
+        }
+
+        let wait = match retry_after {
+            Some(retry_after) => Duration::from_secs(retry_after),
+            None => delay.mul_f64(rand::random_range(0.75..1.25)),
+        };
+        match next_request {
+            Some(next_request) if attempt < state.max_retries && wait <= MAX_RETRY_WAIT => {
+                tracing::warn!(%status, attempt, ?wait, "Retrying GitHub request");
+                tokio::time::sleep(wait).await;
+                request = next_request;
+                delay *= 2;
+                attempt += 1;
+            }
+            _ if rate_limited => {
+                return Err(GitHubTokenError::RateLimited {
+                    retry_after_seconds: retry_after,
+                });
+            }
+            _ => break response,
+        }
+    };
     let status = response.status();
 
     if status.is_success() {
-        response.json().await.map_err(GitHubTokenError::Http)
+        Ok(response)
     } else {
         let url = response.url().to_string();
-        let text = response.text().await.map_err(GitHubTokenError::Http)?;
+        let text = response.text().await.map_err(GitHubTokenError::from_http)?;
         // GitHub usually sends error responses as JSON, but if there is an upstream error with
         // GitHub non-JSON might be returned. Gracefully handle that.
         match serde_json::from_str(&text) {
@@ -214,12 +743,69 @@ where
     }
 }
 
+/// Find the URL of the next page of a paginated response in its `Link` header, formatted like
+/// `<https://api.github.com/...?page=2>; rel="next", <https://api.github.com/...?page=5>; rel="last"`.
+fn next_page(response: &Response) -> Option<String> {
+    response
+        .headers()
+        .get("link")?
+        .to_str()
+        .ok()?
+        .split(',')
+        .find_map(|link| {
+            let (url, params) = link.split_once(';')?;
+            params
+                .split(';')
+                .any(|param| param.trim() == r#"rel="next""#)
+                .then(|| {
+                    url.trim()
+                        .trim_start_matches('<')
+                        .trim_end_matches('>')
+                        .to_string()
+                })
+        })
+}
+
+/// Parse the number of seconds GitHub asks to wait before retrying, if any.
+/// Whether GitHub rejected the request because of a rate limit. Besides 429 responses, GitHub
+/// enforces its secondary rate limits and exhausted primary rate limits with 403 responses, which
+/// can only be told apart from permission errors by their headers.
+fn is_rate_limited(response: &Response, retry_after: Option<u64>) -> bool {
+    match response.status() {
+        StatusCode::TOO_MANY_REQUESTS => true,
+        StatusCode::FORBIDDEN => {
+            retry_after.is_some()
+                || response
+                    .headers()
+                    .get("x-ratelimit-remaining")
+                    .is_some_and(|remaining| remaining == "0")
+        }
+        _ => false,
+    }
+}
+
+fn retry_after(response: &Response) -> Option<u64> {
+    response
+        .headers()
+        .get("retry-after")?
+        .to_str()
+        .ok()?
+        .parse()
+        .ok()
+}
+
 #[derive(Debug, thiserror::Error)]
 pub enum GitHubTokenError {
     #[error("GitHub credentials are not configured for this instance of oidcx")]
     NoCredentials,
     #[error("failed to read the GitHub App private key located at {}", .0.display())]
     ReadPrivateKey(PathBuf, #[source] std::io::Error),
+    #[error(
+        "the environment variable {0} containing the GitHub App private key is not set or not valid UTF-8"
+    )]
+    ReadPrivateKeyEnv(String),
+    #[error("exactly one of private_key_path and private_key_env must be set for GitHub App {0}")]
+    PrivateKeySource(String),
     #[error("Failed to load the GitHub App private key")]
     LoadPrivateKey(#[source] jsonwebtoken::errors::Error),
     #[error("Failed to encode the JWT")]
@@ -229,34 +815,329 @@ pub enum GitHubTokenError {
     #[error("The repositories requested for this token belong to different organizations")]
     DifferentOrgs,
     #[error("The requested token asked for access to no repositories")]
-    NoRepositories,
+    NoRepositoriesWithSelectedMode,
+    #[error(
+        "Tokens for all the repositories of an owner are not allowed by this instance of oidcx"
+    )]
+    AllRepositoriesNotAllowed,
+    #[error("Tokens for all repositories must set the owner, and no repositories")]
+    InvalidAllRepositoriesRequest,
+    #[error("The owner can only be set when requesting all repositories")]
+    OwnerWithSelectedRepositories,
+    #[error("The repository {0} is not allowed by this instance of oidcx")]
+    RepositoryNotAllowed(String),
+    #[error("The allowed repository pattern {0} is invalid")]
+    InvalidRepositoryPattern(String, #[source] glob::PatternError),
+    #[error("Failed to build the HTTP client")]
+    BuildClient(#[source] reqwest::Error),
+    #[error("The proxy configuration is invalid")]
+    ProxyConfig(#[source] reqwest::Error),
     #[error("HTTP error")]
     Http(#[source] reqwest::Error),
+    #[error("Request to GitHub timed out")]
+    Timeout(#[source] reqwest::Error),
+    #[error(
+        "Rate limited by GitHub{}",
+        .retry_after_seconds
+            .map(|seconds| format!(", retry after {seconds} seconds"))
+            .unwrap_or_default()
+    )]
+    RateLimited { retry_after_seconds: Option<u64> },
     #[error("Request to {0} failed with status {1}: {2}")]
     GitHubError(String, StatusCode, String),
     #[error("The permission {0} is requested multiple times")]
     DuplicatePermission(String),
     #[error("The permission string {0} is not a valid permission")]
     NotAPermission(String),
+    #[error("The level {level} of the permission {permission} is not one of read, write or admin")]
+    InvalidPermissionLevel { permission: String, level: String },
+    #[error("The permission {0} is not allowed by this instance of oidcx")]
+    UnknownPermission(String),
     #[error("oidcx's GitHub App is not installed on {0}")]
     AppNotInstalled(String),
+    #[error("oidcx's GitHub App installation on {namespace} is suspended")]
+    InstallationSuspended { namespace: String },
+    #[error("No GitHub App is configured for {0}")]
+    NoAppForOrg(String),
 }
 
 impl GitHubTokenError {
+    fn from_http(err: reqwest::Error) -> Self {
+        if err.is_timeout() {
+            GitHubTokenError::Timeout(err)
+        } else {
+            GitHubTokenError::Http(err)
+        }
+    }
+
     pub fn safe_to_expose(&self) -> bool {
         match self {
             GitHubTokenError::ReadPrivateKey(..)
+            | GitHubTokenError::ReadPrivateKeyEnv(..)
+            | GitHubTokenError::PrivateKeySource(..)
             | GitHubTokenError::LoadPrivateKey(..)
             | GitHubTokenError::EncodeJwt(..)
+            | GitHubTokenError::BuildClient(..)
+            | GitHubTokenError::ProxyConfig(..)
+            | GitHubTokenError::InvalidRepositoryPattern(..)
             | GitHubTokenError::Http(..) => false,
             GitHubTokenError::NoCredentials
             | GitHubTokenError::NotAGitHubRepository(..)
             | GitHubTokenError::DifferentOrgs
-            | GitHubTokenError::NoRepositories
+            | GitHubTokenError::NoRepositoriesWithSelectedMode
+            | GitHubTokenError::AllRepositoriesNotAllowed
+            | GitHubTokenError::InvalidAllRepositoriesRequest
+            | GitHubTokenError::OwnerWithSelectedRepositories
+            | GitHubTokenError::RepositoryNotAllowed(..)
             | GitHubTokenError::DuplicatePermission(..)
             | GitHubTokenError::GitHubError(..)
+            | GitHubTokenError::Timeout(..)
+            | GitHubTokenError::RateLimited { .. }
             | GitHubTokenError::AppNotInstalled(..)
-            | GitHubTokenError::NotAPermission(..) => true,
+            | GitHubTokenError::InstallationSuspended { .. }
+            | GitHubTokenError::NoAppForOrg(..)
+            | GitHubTokenError::NotAPermission(..)
+            | GitHubTokenError::InvalidPermissionLevel { .. }
+            | GitHubTokenError::UnknownPermission(..) => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{
+        MockResponse, MockServer, github_settings, github_tokens, mock_github_installation,
+    };
+
+    static INSTALLATIONS: &str = "/app/installations";
+
+    async fn github_with_retries(max_retries: u32) -> (MockServer, GitHubTokens) {
+        let server = MockServer::start().await;
+        let mut settings = github_settings(&server);
+        settings.max_retries = max_retries;
+        (server, github_tokens(settings))
+    }
+
+    fn retry_now(status: u16) -> MockResponse {
+        MockResponse::json(status, serde_json::json!({ "message": "Try again" }))
+            .with_header("retry-after", "0")
+    }
+
+    #[tokio::test]
+    async fn server_errors_are_retried() {
+        let (server, github) = github_with_retries(3).await;
+        server.respond_sequence(
+            INSTALLATIONS,
+            vec![
+                retry_now(502),
+                MockResponse::json(200, serde_json::json!([])),
+            ],
+        );
+
+        assert!(github.list_installations().await.unwrap().is_empty());
+        assert_eq!(server.request_count(INSTALLATIONS), 2);
+    }
+
+    #[tokio::test]
+    async fn requests_are_attempted_max_retries_times() {
+        let (server, github) = github_with_retries(3).await;
+        server.respond(INSTALLATIONS, retry_now(503));
+
+        let err = github.list_installations().await.unwrap_err();
+        assert!(matches!(
+            err,
+            GitHubTokenError::GitHubError(_, StatusCode::SERVICE_UNAVAILABLE, _)
+        ));
+        assert_eq!(server.request_count(INSTALLATIONS), 3);
+    }
+
+    #[tokio::test]
+    async fn secondary_rate_limits_are_retried() {
+        let (server, github) = github_with_retries(3).await;
+        server.respond_sequence(
+            INSTALLATIONS,
+            vec![
+                retry_now(403),
+                MockResponse::json(200, serde_json::json!([])),
+            ],
+        );
+
+        assert!(github.list_installations().await.unwrap().is_empty());
+        assert_eq!(server.request_count(INSTALLATIONS), 2);
+    }
+
+    #[tokio::test]
+    async fn permission_errors_are_not_retried() {
+        let (server, github) = github_with_retries(3).await;
+        server.respond(
+            INSTALLATIONS,
+            MockResponse::json(403, serde_json::json!({ "message": "Forbidden" })),
+        );
+
+        let err = github.list_installations().await.unwrap_err();
+        assert!(matches!(
+            err,
+            GitHubTokenError::GitHubError(_, StatusCode::FORBIDDEN, _)
+        ));
+        assert_eq!(server.request_count(INSTALLATIONS), 1);
+    }
+
+    /// GitHub settings allowing tokens for all the repositories of the owners matching `patterns`.
+    fn all_repositories_settings(server: &MockServer, patterns: &[&str]) -> SettingsGitHub {
+        let mut settings = github_settings(server);
+        settings.allow_all_repositories = true;
+        settings.allowed_repository_patterns =
+            Some(patterns.iter().map(|pattern| pattern.to_string()).collect());
+        settings
+    }
+
+    fn all_repositories_request(owner: Option<&str>, repositories: &[&str]) -> GitHubTokenRequest {
+        GitHubTokenRequest {
+            repositories: repositories.iter().map(|repo| repo.to_string()).collect(),
+            permissions: vec!["contents:read".into()],
+            repository_selection: Some(RepositorySelection::All),
+            owner: owner.map(Into::into),
         }
     }
+
+    #[tokio::test]
+    async fn all_repositories_must_be_allowed() {
+        let (server, github) = github_with_retries(0).await;
+        mock_github_installation(&server, "oxidecomputer");
+
+        let err = github
+            .get(&all_repositories_request(Some("oxidecomputer"), &[]))
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(err, GitHubTokenError::AllRepositoriesNotAllowed),
+            "{err}"
+        );
+        assert!(server.requests().is_empty());
+    }
+
+    #[tokio::test]
+    async fn all_repositories_need_an_owner_and_no_repositories() {
+        let server = MockServer::start().await;
+        let github = github_tokens(all_repositories_settings(&server, &["oxidecomputer/*"]));
+
+        for request in [
+            all_repositories_request(None, &[]),
+            all_repositories_request(Some("oxidecomputer"), &["oxidecomputer/oidcx"]),
+        ] {
+            let err = github.get(&request).await.unwrap_err();
+            assert!(
+                matches!(err, GitHubTokenError::InvalidAllRepositoriesRequest),
+                "{err}"
+            );
+        }
+
+        let err = github
+            .get(&GitHubTokenRequest {
+                repository_selection: Some(RepositorySelection::Selected),
+                ..all_repositories_request(Some("oxidecomputer"), &["oxidecomputer/oidcx"])
+            })
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(err, GitHubTokenError::OwnerWithSelectedRepositories),
+            "{err}"
+        );
+        assert!(server.requests().is_empty());
+    }
+
+    #[tokio::test]
+    async fn all_repositories_need_a_pattern_covering_the_owner() {
+        let server = MockServer::start().await;
+        let github = github_tokens(all_repositories_settings(&server, &["oxidecomputer/oidcx"]));
+
+        let err = github
+            .get(&all_repositories_request(Some("oxidecomputer"), &[]))
+            .await
+            .unwrap_err();
+        let GitHubTokenError::RepositoryNotAllowed(repository) = err else {
+            panic!("unexpected error: {err}");
+        };
+        assert_eq!(repository, "oxidecomputer/*");
+    }
+
+    #[tokio::test]
+    async fn all_repositories_tokens_leave_out_the_repositories() {
+        let server = MockServer::start().await;
+        mock_github_installation(&server, "oxidecomputer");
+        let github = github_tokens(all_repositories_settings(&server, &["oxidecomputer/*"]));
+
+        let token = github
+            .get(&all_repositories_request(Some("oxidecomputer"), &[]))
+            .await
+            .unwrap();
+        assert_eq!(token.access_token.expose(), "ghs_mock");
+        let request = server
+            .requests()
+            .into_iter()
+            .find(|request| request.path == "/app/installations/1/access_tokens")
+            .unwrap();
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&request.body).unwrap(),
+            serde_json::json!({ "permissions": { "contents": "read" } })
+        );
+    }
+
+    #[tokio::test]
+    async fn suspended_installations_are_reported() {
+        let (server, github) = github_with_retries(3).await;
+        mock_github_installation(&server, "oxidecomputer");
+        server.respond(
+            "/app/installations/1/access_tokens",
+            MockResponse::json(
+                403,
+                serde_json::json!({ "message": "This installation has been suspended" }),
+            ),
+        );
+
+        let err = github
+            .get(&GitHubTokenRequest {
+                repositories: vec!["oxidecomputer/oidcx".into()],
+                permissions: vec!["contents:read".into()],
+                repository_selection: None,
+                owner: None,
+            })
+            .await
+            .unwrap_err();
+        assert!(err.safe_to_expose());
+        let GitHubTokenError::InstallationSuspended { namespace } = err else {
+            panic!("unexpected error: {err}");
+        };
+        assert_eq!(namespace, "oxidecomputer");
+        assert_eq!(
+            server.request_count("/app/installations/1/access_tokens"),
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn rate_limits_report_when_to_retry() {
+        let (server, github) = github_with_retries(3).await;
+        // Waiting longer than MAX_RETRY_WAIT fails right away.
+        server.respond(
+            INSTALLATIONS,
+            MockResponse::json(429, serde_json::json!({ "message": "Slow down" }))
+                .with_header("retry-after", "120"),
+        );
+
+        let err = github.list_installations().await.unwrap_err();
+        assert!(matches!(
+            err,
+            GitHubTokenError::RateLimited {
+                retry_after_seconds: Some(120)
+            }
+        ));
+        assert!(err.safe_to_expose());
+        assert_eq!(
+            err.to_string(),
+            "Rate limited by GitHub, retry after 120 seconds"
+        );
+        assert_eq!(server.request_count(INSTALLATIONS), 1);
+    }
 }
@@ -2,22 +2,38 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
-use oxide::{ByteStream, Client, ClientConfig, ClientConsoleAuthExt, OxideAuthError};
+use chrono::{DateTime, Utc};
+use futures_util::TryStreamExt;
+use futures_util::future::join_all;
+use oxide::{
+    ByteStream, Client, ClientConfig, ClientConsoleAuthExt, ClientCurrentUserExt,
+    ClientProjectsExt, OxideAuthError,
+};
 use schemars::JsonSchema;
 use serde::Deserialize;
-use std::{collections::HashMap, path::PathBuf};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 use tap::TapFallible;
 use thiserror::Error;
+use tracing::instrument;
 
 use crate::{
-    endpoints::Token,
+    endpoints::{SecretToken, Token},
     oauth::{DeviceAccessTokenError, DeviceAccessTokenGrant, DeviceAuthorizationResponse},
-    settings::Settings,
+    settings::{OxideStepTimeouts, Settings},
     util::{ByteStreamError, parse_bytestream},
 };
 
 static CLIENT_ID: &str = "730ae5f1-a728-4a5d-9a06-cf09b653cca6";
 
+/// How long to wait before the second attempt to reach a silo at startup, doubled for each
+/// following attempt.
+static STARTUP_RETRY_DELAY: Duration = Duration::from_secs(1);
+
 #[derive(Debug, Error)]
 pub enum OxideError {
     #[error("Error reading response")]
@@ -26,23 +42,44 @@ pub enum OxideError {
     DeviceAuthRequest(#[from] DeviceAccessTokenError),
     #[error("Silo token located at {0} is malformed")]
     ReadToken(PathBuf, #[source] std::io::Error),
+    #[error("Failed to read the silo tokens in {0}")]
+    ReadTokenDirectory(PathBuf, #[source] std::io::Error),
     #[error("The silo {0} is not configured in this instance of oidcx")]
     SiloNotConfigured(String),
     #[error("Failed to authenticate with silo {0}")]
     AuthFailed(String, #[source] OxideAuthError),
     #[error("Remote service error")]
-    Oxide(#[from] oxide::Error<oxide::types::Error>),
+    Oxide(oxide::Error<oxide::types::Error>),
     #[error("Remote service error")]
-    OxideByteError(#[from] oxide::Error<ByteStream>),
+    OxideByteError(oxide::Error<ByteStream>),
+    #[error("Request to the silo timed out")]
+    Timeout(#[source] reqwest::Error),
     #[error("The Oxide token provider is not configured")]
     NotConfigured,
     #[error("Tokens with no expiration are not allowed")]
     NoExpirationDisallowed,
     #[error("The duration of this token is more than the maximum of {0} seconds")]
     TooLongExpiration(u32),
+    #[error("The duration of this token is less than the minimum of {0} seconds")]
+    TooShortExpiration(u32),
+    #[error("Timed out waiting for the {0} step of the device flow")]
+    StepTimeout(&'static str),
 }
 
 impl OxideError {
+    /// Whether the error might not happen again, like the silo being unreachable or failing on
+    /// its side. Rejected credentials and invalid requests are not transient.
+    fn is_transient(&self) -> bool {
+        match self {
+            OxideError::Timeout(..) => true,
+            OxideError::Oxide(err) => {
+                matches!(err, oxide::Error::CommunicationError(..))
+                    || err.status().is_some_and(|status| status.is_server_error())
+            }
+            _ => false,
+        }
+    }
+
     pub fn safe_to_expose(&self) -> bool {
         match self {
             OxideError::ByteStream(..)
@@ -50,11 +87,35 @@ impl OxideError {
             | OxideError::AuthFailed(..)
             | OxideError::Oxide(..)
             | OxideError::OxideByteError(..)
-            | OxideError::ReadToken(..) => false,
+            | OxideError::ReadToken(..)
+            | OxideError::ReadTokenDirectory(..) => false,
             OxideError::SiloNotConfigured(..)
             | OxideError::NotConfigured
             | OxideError::NoExpirationDisallowed
-            | OxideError::TooLongExpiration(..) => true,
+            | OxideError::TooLongExpiration(..)
+            | OxideError::TooShortExpiration(..)
+            | OxideError::StepTimeout(..)
+            | OxideError::Timeout(..) => true,
+        }
+    }
+}
+
+// Timeouts are reported separately, as unlike other communication errors the caller can act on
+// them (by retrying later).
+impl From<oxide::Error<oxide::types::Error>> for OxideError {
+    fn from(err: oxide::Error<oxide::types::Error>) -> Self {
+        match err {
+            oxide::Error::CommunicationError(err) if err.is_timeout() => OxideError::Timeout(err),
+            err => OxideError::Oxide(err),
+        }
+    }
+}
+
+impl From<oxide::Error<ByteStream>> for OxideError {
+    fn from(err: oxide::Error<ByteStream>) -> Self {
+        match err {
+            oxide::Error::CommunicationError(err) if err.is_timeout() => OxideError::Timeout(err),
+            err => OxideError::OxideByteError(err),
         }
     }
 }
@@ -62,12 +123,39 @@ impl OxideError {
 #[derive(Clone, Debug, Deserialize, JsonSchema, Hash, PartialEq, Eq)]
 pub struct OxideTokenRequest {
     pub silo: String,
+    /// How long the token should be valid for, in `duration_unit`. 0 requests a token that never
+    /// expires.
     pub duration: u32,
+    /// Unit of `duration`, defaulting to seconds.
+    #[serde(default)]
+    pub duration_unit: Option<DurationUnit>,
 }
 
-#[derive(Debug)]
+impl OxideTokenRequest {
+    /// The requested duration, in seconds. Durations too large to represent are capped, so that
+    /// they are rejected for exceeding the maximum duration.
+    pub fn duration_seconds(&self) -> u32 {
+        let multiplier = match self.duration_unit.unwrap_or(DurationUnit::Seconds) {
+            DurationUnit::Seconds => 1,
+            DurationUnit::Minutes => 60,
+            DurationUnit::Hours => 60 * 60,
+        };
+        self.duration.saturating_mul(multiplier)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, JsonSchema, Hash, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum DurationUnit {
+    Seconds,
+    Minutes,
+    Hours,
+}
+
+/// The default instance is not configured, and fails to generate any token.
+#[derive(Clone, Debug, Default)]
 pub struct OxideTokens {
-    state: Option<State>,
+    state: Option<Arc<State>>,
 }
 
 impl OxideTokens {
@@ -76,11 +164,24 @@ impl OxideTokens {
             return Ok(Self { state: None });
         };
 
+        let mut silos = settings.silos.clone();
+        if let Some(directory) = &settings.token_directory {
+            for (silo, token_path) in discover_silo_tokens(directory)? {
+                if silos.contains_key(&silo) {
+                    continue;
+                }
+                tracing::info!(silo, token_path = %token_path.display(), "Discovered silo token");
+                silos.insert(silo, token_path);
+            }
+        }
+
         let mut clients = HashMap::new();
-        for (silo, token_path) in &settings.silos {
+        for (silo, token_path) in &silos {
             let token = std::fs::read_to_string(&token_path)
                 .map_err(|e| OxideError::ReadToken(token_path.clone(), e))?;
-            let config = ClientConfig::default().with_host_and_token(silo, token);
+            let config = ClientConfig::default()
+                .with_host_and_token(silo, token)
+                .with_timeout(settings.api_timeout_seconds);
             clients.insert(
                 silo.clone(),
                 Client::new_authenticated_config(&config)
@@ -88,101 +189,412 @@ impl OxideTokens {
             );
         }
         Ok(Self {
-            state: Some(State {
+            state: Some(Arc::new(State {
                 clients,
                 allow_tokens_without_expiry: settings.allow_tokens_without_expiry,
                 max_duration: settings.max_duration,
-            }),
+                min_duration: settings.min_duration,
+                step_timeouts: settings.step_timeouts.clone().unwrap_or_default(),
+                project_count_cache_ttl: chrono::Duration::seconds(
+                    settings.project_count_cache_ttl as _,
+                ),
+                project_count_cache: Mutex::new(HashMap::new()),
+                startup_retry_count: settings.startup_retry_count,
+            })),
         })
     }
 
+    #[instrument(name = "token.generate", skip(self))]
     pub async fn get(&self, request: &OxideTokenRequest) -> Result<Token, OxideError> {
         let Some(state) = &self.state else {
             return Err(OxideError::NotConfigured.into());
         };
 
-        if request.duration <= 0 && !state.allow_tokens_without_expiry {
+        let duration = request.duration_seconds();
+        if duration <= 0 && !state.allow_tokens_without_expiry {
             return Err(OxideError::NoExpirationDisallowed.into());
         }
-        if request.duration > state.max_duration {
+        if duration > state.max_duration {
             return Err(OxideError::TooLongExpiration(state.max_duration).into());
         }
+        // Tokens without expiration are not subject to the minimum duration.
+        if let Some(min_duration) = state.min_duration
+            && duration != 0
+            && duration < min_duration
+        {
+            return Err(OxideError::TooShortExpiration(min_duration));
+        }
+        if duration > state.max_duration / 10 * 9 {
+            tracing::warn!(
+                duration,
+                max_duration = state.max_duration,
+                "Requested token duration is close to the maximum"
+            );
+        }
 
         let client = state
             .clients
             .get(&request.silo)
             .ok_or_else(|| OxideError::SiloNotConfigured(request.silo.clone()))?;
 
-        let device_response = match client
-            .device_auth_request()
-            .body_map(|body| {
-                body.client_id(CLIENT_ID)
-                    .ttl_seconds(if request.duration == 0 {
-                        None
-                    } else {
-                        Some(request.duration.try_into().unwrap())
+        let timeouts = &state.step_timeouts;
+        let device_response = with_step_timeout(
+            timeouts.device_auth_request,
+            "device authorization request",
+            async {
+                let response: Result<DeviceAuthorizationResponse, OxideError> = match client
+                    .device_auth_request()
+                    .body_map(|body| {
+                        body.client_id(CLIENT_ID).ttl_seconds(if duration == 0 {
+                            None
+                        } else {
+                            Some(duration.try_into().unwrap())
+                        })
                     })
-            })
-            .send()
-            .await
-        {
-            Ok(data) => {
-                parse_bytestream::<DeviceAuthorizationResponse>(data.into_inner().into_inner())
-                    .await?
-            }
-            Err(err) => {
-                tracing::error!(?err, "Failed to issue device auth request");
-
-                // Attempt to parse the error response
-                match err {
-                    oxide::Error::ErrorResponse(stream) => {
-                        let error_data =
-                            parse_bytestream::<DeviceAccessTokenError>(stream.into_inner_stream())
+                    .send()
+                    .await
+                {
+                    Ok(data) => Ok(parse_bytestream::<DeviceAuthorizationResponse>(
+                        data.into_inner().into_inner(),
+                    )
+                    .await?),
+                    Err(err) => {
+                        tracing::error!(?err, "Failed to issue device auth request");
+
+                        // Attempt to parse the error response
+                        match err {
+                            oxide::Error::ErrorResponse(stream) => {
+                                let error_data = parse_bytestream::<DeviceAccessTokenError>(
+                                    stream.into_inner_stream(),
+                                )
                                 .await?;
-                        return Err(error_data.into());
+                                Err(error_data.into())
+                            }
+                            _ => Err(err.into()),
+                        }
                     }
-                    _ => return Err(err.into()),
-                }
-            }
-        };
+                };
+                response
+            },
+        )
+        .await?;
 
         // Once we have the user code, submit it to the API to confirm the request
-        client
-            .device_auth_confirm()
-            .body_map(|body| body.user_code(device_response.user_code))
-            .send()
-            .await
-            .tap_err(|err| {
-                tracing::error!(?err, "Failed to confirm device auth request");
-            })?;
+        with_step_timeout(
+            timeouts.device_auth_confirm,
+            "device authorization confirmation",
+            async {
+                client
+                    .device_auth_confirm()
+                    .body_map(|body| body.user_code(device_response.user_code))
+                    .send()
+                    .await
+                    .tap_err(|err| {
+                        tracing::error!(?err, "Failed to confirm device auth request");
+                    })?;
+                Ok::<_, OxideError>(())
+            },
+        )
+        .await?;
 
         // Given that we are performing these requests serially, the token should be
         // ready by the time we make this call
-        let data = client
-            .device_access_token()
-            .body_map(|body| {
-                body.client_id(CLIENT_ID)
-                    .device_code(device_response.device_code)
-                    .grant_type("urn:ietf:params:oauth:grant-type:device_code")
+        let access_token_response =
+            with_step_timeout(timeouts.device_access_token, "device access token", async {
+                let data = client
+                    .device_access_token()
+                    .body_map(|body| {
+                        body.client_id(CLIENT_ID)
+                            .device_code(device_response.device_code)
+                            .grant_type("urn:ietf:params:oauth:grant-type:device_code")
+                    })
+                    .send()
+                    .await
+                    .tap_err(|err| {
+                        tracing::error!(?err, "Failed to retrieve device access token");
+                    })?
+                    .into_inner()
+                    .into_inner();
+                Ok::<_, OxideError>(parse_bytestream::<DeviceAccessTokenGrant>(data).await?)
             })
-            .send()
-            .await
-            .tap_err(|err| {
-                tracing::error!(?err, "Failed to retrieve device access token");
-            })?
-            .into_inner()
-            .into_inner();
-        let access_token_response = parse_bytestream::<DeviceAccessTokenGrant>(data).await?;
+            .await?;
 
         Ok(Token {
-            access_token: access_token_response.access_token,
+            access_token: SecretToken::new(access_token_response.access_token),
+            extra: None,
+            expires_at: None,
         })
     }
+
+    /// Count the projects visible to the credential configured for the silo.
+    #[instrument(skip(self))]
+    pub async fn project_count(&self, silo: &str) -> Result<i64, OxideError> {
+        let Some(state) = &self.state else {
+            return Err(OxideError::NotConfigured);
+        };
+
+        // We are not holding the lock across the await point below.
+        {
+            let cache = state.project_count_cache.lock().unwrap();
+            if let Some(cached) = cache.get(silo)
+                && cached.expires_at >= Utc::now()
+            {
+                return Ok(cached.count);
+            }
+        }
+
+        let client = state
+            .clients
+            .get(silo)
+            .ok_or_else(|| OxideError::SiloNotConfigured(silo.into()))?;
+        let count = client
+            .project_list()
+            .stream()
+            .try_fold(0, |count, _| async move { Ok(count + 1) })
+            .await
+            .tap_err(|err| {
+                tracing::error!(?err, "Failed to list projects");
+            })?;
+
+        state.project_count_cache.lock().unwrap().insert(
+            silo.into(),
+            CachedProjectCount {
+                count,
+                expires_at: Utc::now() + state.project_count_cache_ttl,
+            },
+        );
+        Ok(count)
+    }
+
+    /// Check that every configured silo is reachable and accepts its credential, by fetching the
+    /// user the credential belongs to. All silos are checked concurrently, and transient failures
+    /// are retried with an exponential backoff, up to `startup_retry_count` attempts per silo.
+    pub async fn validate_silo_connectivity(&self) -> Vec<(String, Result<(), OxideError>)> {
+        let Some(state) = &self.state else {
+            return Vec::new();
+        };
+
+        join_all(state.clients.iter().map(|(silo, client)| async move {
+            let mut attempt = 1;
+            let result = loop {
+                let result = client
+                    .current_user_view()
+                    .send()
+                    .await
+                    .map(|_| ())
+                    .map_err(OxideError::from);
+                match result {
+                    Err(err) if err.is_transient() && attempt < state.startup_retry_count => {
+                        let delay = STARTUP_RETRY_DELAY * 2u32.pow(attempt - 1);
+                        tracing::warn!(
+                            silo,
+                            attempt,
+                            delay_seconds = delay.as_secs(),
+                            error = ?err,
+                            "Failed to reach the silo, retrying"
+                        );
+                        tokio::time::sleep(delay).await;
+                        attempt += 1;
+                    }
+                    result => break result,
+                }
+            };
+            (silo.clone(), result)
+        }))
+        .await
+    }
+}
+
+/// Find the `<host>.token` files in the directory, returning the silo URL each token is for.
+fn discover_silo_tokens(directory: &Path) -> Result<Vec<(String, PathBuf)>, OxideError> {
+    let entries = std::fs::read_dir(directory)
+        .map_err(|e| OxideError::ReadTokenDirectory(directory.into(), e))?;
+    let mut tokens = Vec::new();
+    for entry in entries {
+        let path = entry
+            .map_err(|e| OxideError::ReadTokenDirectory(directory.into(), e))?
+            .path();
+        if path
+            .extension()
+            .is_some_and(|extension| extension == "token")
+            && let Some(host) = path.file_stem().and_then(|stem| stem.to_str())
+        {
+            tokens.push((format!("https://{host}"), path));
+        }
+    }
+    Ok(tokens)
+}
+
+/// Run one step of the device flow, failing if it doesn't complete within the timeout.
+async fn with_step_timeout<T>(
+    timeout: Option<u64>,
+    step: &'static str,
+    future: impl Future<Output = Result<T, OxideError>>,
+) -> Result<T, OxideError> {
+    match timeout {
+        Some(timeout) => tokio::time::timeout(Duration::from_secs(timeout), future)
+            .await
+            .map_err(|_| {
+                tracing::error!(step, timeout, "Device flow step timed out");
+                OxideError::StepTimeout(step)
+            })?,
+        None => future.await,
+    }
 }
 
 #[derive(Debug)]
 struct State {
+    /// One client per silo, built at startup. Each client owns a `reqwest::Client`, whose
+    /// connection pool is reused by all the API calls made to the silo (including the steps of
+    /// the device flow), so only the first request to a silo pays for the TCP and TLS handshakes.
     clients: HashMap<String, Client>,
     allow_tokens_without_expiry: bool,
     max_duration: u32,
+    min_duration: Option<u32>,
+    step_timeouts: OxideStepTimeouts,
+    project_count_cache_ttl: chrono::Duration,
+    project_count_cache: Mutex<HashMap<String, CachedProjectCount>>,
+    startup_retry_count: u32,
+}
+
+#[derive(Debug)]
+struct CachedProjectCount {
+    count: i64,
+    expires_at: DateTime<Utc>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{MockResponse, MockServer, oxide_settings, test_settings};
+
+    fn oxide_request(request: serde_json::Value) -> OxideTokenRequest {
+        serde_json::from_value(request).unwrap()
+    }
+
+    #[test]
+    fn durations_are_converted_to_seconds() {
+        let silo = "https://example.sys.oxide.computer";
+        let seconds = |duration: u32, unit: Option<&str>| {
+            let mut request = serde_json::json!({ "silo": silo, "duration": duration });
+            if let Some(unit) = unit {
+                request["duration_unit"] = unit.into();
+            }
+            oxide_request(request).duration_seconds()
+        };
+
+        assert_eq!(seconds(3600, None), 3600);
+        assert_eq!(seconds(3600, Some("seconds")), 3600);
+        assert_eq!(seconds(60, Some("minutes")), 3600);
+        assert_eq!(seconds(2, Some("hours")), 7200);
+        assert_eq!(seconds(0, Some("hours")), 0);
+        // Capped so that it's rejected for exceeding the maximum, rather than wrapping around.
+        assert_eq!(seconds(u32::MAX, Some("hours")), u32::MAX);
+    }
+
+    #[test]
+    fn unknown_duration_units_are_rejected() {
+        let request = serde_json::json!({
+            "silo": "https://example.sys.oxide.computer",
+            "duration": 1,
+            "duration_unit": "days",
+        });
+        assert!(serde_json::from_value::<OxideTokenRequest>(request).is_err());
+    }
+
+    #[tokio::test]
+    async fn maximum_duration_applies_to_converted_durations() {
+        let server = MockServer::start().await;
+        let mut oxide = oxide_settings(&server);
+        oxide.max_duration = 3600;
+        let settings = test_settings("").with_oxide(oxide).build().unwrap();
+        let tokens = OxideTokens::new(&settings).unwrap();
+
+        let err = tokens
+            .get(&oxide_request(serde_json::json!({
+                "silo": server.url(),
+                "duration": 2,
+                "duration_unit": "hours",
+            })))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, OxideError::TooLongExpiration(3600)), "{err}");
+        assert!(server.requests().is_empty());
+    }
+
+    #[tokio::test]
+    async fn device_flow_reuses_the_connection_to_the_silo() {
+        let server = MockServer::start().await;
+        server.respond(
+            "/device/auth",
+            MockResponse::json(
+                200,
+                serde_json::json!({ "device_code": "device", "user_code": "user" }),
+            ),
+        );
+        server.respond("/device/confirm", MockResponse::empty(204));
+        server.respond(
+            "/device/token",
+            MockResponse::json(200, serde_json::json!({ "access_token": "oxide-token" })),
+        );
+        let settings = test_settings("")
+            .with_oxide(oxide_settings(&server))
+            .build()
+            .unwrap();
+        let tokens = OxideTokens::new(&settings).unwrap();
+        let request = OxideTokenRequest {
+            silo: server.url(),
+            duration: 3600,
+            duration_unit: None,
+        };
+
+        for _ in 0..2 {
+            let token = tokens.get(&request).await.unwrap();
+            assert_eq!(token.access_token.expose(), "oxide-token");
+        }
+        assert_eq!(server.request_count("/device/token"), 2);
+        assert_eq!(server.connection_count(), 1);
+    }
+
+    #[tokio::test]
+    async fn unreachable_silos_are_retried_at_startup() {
+        let server = MockServer::start().await;
+        let unavailable = MockResponse::json(
+            503,
+            serde_json::json!({ "request_id": "mock", "message": "Service Unavailable" }),
+        );
+        server.respond("/v1/me", unavailable);
+        let mut oxide = oxide_settings(&server);
+        oxide.startup_retry_count = 2;
+        let settings = test_settings("").with_oxide(oxide).build().unwrap();
+        let tokens = OxideTokens::new(&settings).unwrap();
+
+        let results = tokens.validate_silo_connectivity().await;
+        assert_eq!(results.len(), 1);
+        let (silo, result) = &results[0];
+        assert_eq!(*silo, server.url());
+        assert!(result.as_ref().is_err_and(OxideError::is_transient));
+        assert_eq!(server.request_count("/v1/me"), 2);
+    }
+
+    #[tokio::test]
+    async fn rejected_silo_credentials_are_not_retried() {
+        let server = MockServer::start().await;
+        server.respond(
+            "/v1/me",
+            MockResponse::json(
+                401,
+                serde_json::json!({ "request_id": "mock", "message": "Unauthorized" }),
+            ),
+        );
+        let settings = test_settings("")
+            .with_oxide(oxide_settings(&server))
+            .build()
+            .unwrap();
+        let tokens = OxideTokens::new(&settings).unwrap();
+
+        let results = tokens.validate_silo_connectivity().await;
+        assert!(results[0].1.is_err());
+        assert_eq!(server.request_count("/v1/me"), 1);
+    }
 }
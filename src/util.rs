@@ -8,12 +8,17 @@ use serde::de::DeserializeOwned;
 use std::pin::Pin;
 use thiserror::Error;
 
+/// How many bytes of an unparseable body to keep for debugging.
+static PREVIEW_LENGTH: usize = 200;
+
 #[derive(Debug, Error)]
 pub enum ByteStreamError {
     #[error("Failed to read bytes from stream")]
     FailedToRead,
+    // The preview is left out of the message, as it might contain sensitive data and the message
+    // can be returned to clients.
     #[error("Failed to parse read bytes")]
-    FailedToParse,
+    FailedToParse { preview: String },
 }
 
 pub async fn parse_bytestream<T>(
@@ -35,7 +40,70 @@ where
     }
 
     Ok(serde_json::from_slice::<T>(&bytes).map_err(|err| {
-        tracing::error!(?err, "Failed to parse byte stream");
-        ByteStreamError::FailedToParse
+        let preview = String::from_utf8_lossy(&bytes[..bytes.len().min(PREVIEW_LENGTH)]);
+        // Only log the preview at the error level, as it might contain sensitive data.
+        tracing::error!(?err, preview = %preview, "Failed to parse byte stream");
+        ByteStreamError::FailedToParse {
+            preview: preview.into_owned(),
+        }
     })?)
 }
+
+/// Compare two secrets in constant time, so the time taken doesn't reveal how much of the secret
+/// an attacker guessed correctly.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+/// Format the error followed by its causes, skipping the causes already included in the message
+/// of the error wrapping them.
+pub fn error_chain(err: &dyn std::error::Error) -> String {
+    let mut message = err.to_string();
+    let mut source = err.source();
+    while let Some(cause) = source {
+        let cause_message = cause.to_string();
+        if !message.contains(&cause_message) {
+            message = format!("{message}: {cause_message}");
+        }
+        source = cause.source();
+    }
+    message
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stream(
+        chunks: &[&'static str],
+    ) -> Pin<Box<dyn Stream<Item = reqwest::Result<Bytes>> + Send + Sync>> {
+        let chunks = chunks
+            .iter()
+            .map(|chunk| Ok(Bytes::from_static(chunk.as_bytes())))
+            .collect::<Vec<_>>();
+        Box::pin(futures_util::stream::iter(chunks))
+    }
+
+    #[tokio::test]
+    async fn unparseable_bodies_are_previewed() {
+        let err = parse_bytestream::<serde_json::Value>(stream(&["{\"message\": ", "oops}"]))
+            .await
+            .unwrap_err();
+        let ByteStreamError::FailedToParse { preview } = err else {
+            panic!("unexpected error: {err}");
+        };
+        assert_eq!(preview, "{\"message\": oops}");
+    }
+
+    #[tokio::test]
+    async fn previews_are_truncated() {
+        let body: &'static str = "x".repeat(PREVIEW_LENGTH * 2).leak();
+        let err = parse_bytestream::<serde_json::Value>(stream(&[body]))
+            .await
+            .unwrap_err();
+        let ByteStreamError::FailedToParse { preview } = err else {
+            panic!("unexpected error: {err}");
+        };
+        assert_eq!(preview, "x".repeat(PREVIEW_LENGTH));
+    }
+}